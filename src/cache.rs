@@ -15,10 +15,10 @@ use tokio::sync::{Mutex, RwLock};
 use tower::{Layer, Service};
 
 use crate::metrics::CacheMetrics;
+use crate::scrape::Sample;
 use axum::http;
 use hyper::body::Bytes;
 use opentelemetry::KeyValue;
-use prometheus_parse::{self, Sample};
 
 /// Caching primitives used by metrics-proxy.
 ///
@@ -62,7 +62,7 @@ impl From<&Sample> for OrderedLabelSet {
 }
 
 struct SampleCacheEntry {
-    sample: prometheus_parse::Sample,
+    sample: Sample,
     saved_at: Instant,
 }
 
@@ -76,12 +76,7 @@ pub struct SampleCacheStore {
 
 impl SampleCacheStore {
     #[must_use]
-    pub fn get(
-        &self,
-        sample: &prometheus_parse::Sample,
-        when: Instant,
-        staleness: Duration,
-    ) -> Option<Sample> {
+    pub fn get(&self, sample: &Sample, when: Instant, staleness: Duration) -> Option<Sample> {
         let key = OrderedLabelSet::from(sample);
         let value = self.cache.get(&key);
         match value {
@@ -100,7 +95,7 @@ impl SampleCacheStore {
         }
     }
 
-    pub fn put(&mut self, sample: prometheus_parse::Sample, at_: Instant) {
+    pub fn put(&mut self, sample: Sample, at_: Instant) {
         let cache = &mut self.cache;
         cache.insert(
             OrderedLabelSet::from(&sample),
@@ -230,12 +225,17 @@ impl<KeyT: Eq + Hash + Clone + Sync + Send, EntryT: Sync + Send> DeadlineCacher<
 // resource and authentication credentials.
 pub struct CacheLayer {
     cacher: DeadlineCacher<String, CachedResponse>,
+    relayed_headers: Arc<Vec<String>>,
 }
 
 impl CacheLayer {
-    pub fn new(staleness: Duration) -> Self {
+    /// Creates a cache layer.  Since the headers in `relayed_headers`
+    /// are relayed to the backend, responses are cached separately
+    /// for each combination of their values.
+    pub fn new(staleness: Duration, relayed_headers: Vec<String>) -> Self {
         CacheLayer {
             cacher: DeadlineCacher::new(staleness),
+            relayed_headers: Arc::new(relayed_headers),
         }
     }
 }
@@ -246,6 +246,7 @@ impl<S> Layer<S> for CacheLayer {
     fn layer(&self, service: S) -> Self::Service {
         CacheService {
             cacher: self.cacher.clone(),
+            relayed_headers: self.relayed_headers.clone(),
             metrics: CacheMetrics::default(),
             inner: service,
         }
@@ -269,6 +270,7 @@ struct CachedResponse {
 // incoming requests designated as cacheable.
 pub struct CacheService<S> {
     cacher: DeadlineCacher<String, CachedResponse>,
+    relayed_headers: Arc<Vec<String>>,
     metrics: CacheMetrics,
     inner: S,
 }
@@ -302,12 +304,23 @@ where
             },
             request.uri()
         );
-        let cache_key = format!(
-            "{}\n{:?}\n{:?}",
+        // Responses are rendered in the format negotiated through the
+        // Accept header, so responses in different formats are cached apart.
+        // The same goes for any header relayed to the backend.
+        let mut cache_key = format!(
+            "{}\n{:?}\n{:?}\n{:?}",
             request.uri(),
             reqheaders.get("Authorization"),
-            reqheaders.get("Proxy-Authorization")
+            reqheaders.get("Proxy-Authorization"),
+            reqheaders.get_all("Accept").iter().collect::<Vec<_>>()
         );
+        for name in self.relayed_headers.iter() {
+            cache_key.push_str(&format!(
+                "\n{}: {:?}",
+                name,
+                reqheaders.get_all(name.as_str()).iter().collect::<Vec<_>>()
+            ));
+        }
         let client_call = self.inner.call(request);
         let cacher = self.cacher.clone();
         let metrics = self.metrics.clone();
@@ -1,7 +1,8 @@
+use std::collections::VecDeque;
 use std::str::Utf8Error;
 
+use crate::scrape::{Parser, Scrape, TextFormat};
 use hyper::body::Bytes;
-use prometheus_parse;
 use reqwest;
 use reqwest::header;
 
@@ -14,7 +15,6 @@ pub struct HttpError {
 
 pub struct ScrapeResult {
     pub headers: header::HeaderMap,
-    pub series: prometheus_parse::Scrape,
 }
 
 #[derive(Debug)]
@@ -23,6 +23,9 @@ pub enum ScrapeError {
     FetchError(reqwest::Error),
     ParseError(std::io::Error),
     DecodeError(Utf8Error),
+    CredentialsError(std::io::Error),
+    ConflictError(String),
+    LimitError(String),
 }
 
 impl From<reqwest::Error> for ScrapeError {
@@ -31,12 +34,6 @@ impl From<reqwest::Error> for ScrapeError {
     }
 }
 
-impl From<std::io::Error> for ScrapeError {
-    fn from(err: std::io::Error) -> Self {
-        ScrapeError::ParseError(err)
-    }
-}
-
 impl From<Utf8Error> for ScrapeError {
     fn from(err: Utf8Error) -> Self {
         ScrapeError::DecodeError(err)
@@ -49,7 +46,68 @@ impl From<HttpError> for ScrapeError {
     }
 }
 
-/// Scrapes a target and returns a `ScrapeResult`.
+/// A scrape whose response is being received, handing out the metric
+/// families of the response as they are parsed, so the response is never
+/// held in memory as a whole.
+pub struct Scraping {
+    pub headers: header::HeaderMap,
+    response: reqwest::Response,
+    parser: Option<Parser>,
+    // Chunks of the body are split at arbitrary points, so the last
+    // line of a chunk is kept until the chunk that completes it arrives.
+    pending: Vec<u8>,
+    // Families parsed from the last chunk but not yet handed out.
+    parsed: VecDeque<Scrape>,
+}
+
+impl Scraping {
+    /// Returns the next metric family of the response, receiving
+    /// as much of the response as needed to parse it, or `None` once
+    /// all families have been returned.
+    ///
+    /// # Errors
+    /// * `ScrapeError`
+    pub async fn next_family(&mut self) -> Result<Option<Scrape>, ScrapeError> {
+        while self.parsed.is_empty() {
+            let Some(parser) = &mut self.parser else {
+                return Ok(None);
+            };
+            match self.response.chunk().await? {
+                Some(chunk) => {
+                    self.pending.extend_from_slice(&chunk);
+                    let mut start = 0;
+                    while let Some(length) = self.pending[start..].iter().position(|b| *b == b'\n')
+                    {
+                        let line = std::str::from_utf8(&self.pending[start..start + length])?;
+                        if let Some(family) =
+                            parser.parse_line(line).map_err(ScrapeError::ParseError)?
+                        {
+                            self.parsed.push_back(family);
+                        }
+                        start += length + 1;
+                    }
+                    self.pending.drain(..start);
+                }
+                None => {
+                    let mut parser = self.parser.take().unwrap();
+                    if let Some(family) = parser
+                        .parse_line(std::str::from_utf8(&self.pending)?)
+                        .map_err(ScrapeError::ParseError)?
+                    {
+                        self.parsed.push_back(family);
+                    }
+                    self.parsed.push_back(parser.finish());
+                }
+            }
+        }
+        Ok(self.parsed.pop_front())
+    }
+}
+
+/// Scrapes a target, returning as soon as the response headers have
+/// been received.  The metric families of the response are then read
+/// with `Scraping::next_family`.  The `query` parameters are added to
+/// those of the target URL, replacing any parameters with the same name.
 ///
 /// # Errors
 /// * `ScrapeError`
@@ -57,32 +115,92 @@ pub async fn scrape(
     client: reqwest::Client,
     c: &crate::config::ConnectTo,
     h: reqwest::header::HeaderMap,
-) -> Result<ScrapeResult, ScrapeError> {
-    let url = c.url.to_string();
-    let response = client
-        .get(url)
-        .headers(h)
-        .timeout(c.timeout.into())
-        .send()
-        .await?;
+    query: &[(String, String)],
+) -> Result<Scraping, ScrapeError> {
+    let mut url = c.url.clone();
+    if !query.is_empty() {
+        let kept: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| !query.iter().any(|(n, _)| n == name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(kept)
+            .extend_pairs(query);
+    }
+    let mut headers = h;
+    // Configured headers take precedence over those relayed from the client.
+    for (name, value) in &c.headers {
+        headers.insert(name, value.clone());
+    }
+    // Configured credentials take precedence over relayed ones.
+    if c.bearer_token_file.is_some() || c.basic_auth.is_some() {
+        headers.remove(reqwest::header::AUTHORIZATION);
+    }
+    let mut request = client.get(url).headers(headers).timeout(c.timeout.into());
+    // Credentials are read every time, since they may have been rotated.
+    if let Some(token_file) = &c.bearer_token_file {
+        let token = token_file.read().map_err(ScrapeError::CredentialsError)?;
+        request = request.bearer_auth(token);
+    }
+    if let Some(basic_auth) = &c.basic_auth {
+        let password = basic_auth
+            .password_file
+            .read()
+            .map_err(ScrapeError::CredentialsError)?;
+        request = request.basic_auth(&basic_auth.username, Some(password));
+    }
+    let response = request.send().await?;
     let status = response.status();
     let headers = response.headers().clone();
-    let data = response.bytes().await?;
     if status != reqwest::StatusCode::OK {
+        let data = response.bytes().await?;
         return Err(ScrapeError::Non200(HttpError {
             status,
             headers,
             data,
         }));
     }
-    match std::str::from_utf8(data.as_ref()) {
-        Ok(text) => match prometheus_parse::Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))) {
-            Ok(parsed) => Ok(ScrapeResult {
-                headers,
-                series: parsed,
-            }),
-            Err(err) => Err(ScrapeError::ParseError(err)),
-        },
-        Err(err) => Err(ScrapeError::DecodeError(err)),
-    }
+    let format = TextFormat::from_content_type(
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default(),
+    );
+    Ok(Scraping {
+        headers,
+        response,
+        parser: Some(Parser::new(format)),
+        pending: vec![],
+        parsed: VecDeque::new(),
+    })
+}
+
+/// Scrapes several targets concurrently, each with its own client.
+/// Since the metric families of all targets are needed to merge them,
+/// the families of each target are collected rather than handed over
+/// as they are parsed.  Results are returned in the order of `targets`.
+pub async fn scrape_all(
+    targets: &[(reqwest::Client, &crate::config::ConnectTo)],
+    h: reqwest::header::HeaderMap,
+    query: &[(String, String)],
+) -> Vec<Result<(ScrapeResult, Vec<Scrape>), ScrapeError>> {
+    futures_util::future::join_all(targets.iter().map(|(client, c)| {
+        let h = h.clone();
+        async move {
+            let mut scraping = scrape(client.clone(), c, h, query).await?;
+            let mut families = vec![];
+            while let Some(family) = scraping.next_family().await? {
+                families.push(family);
+            }
+            Ok((
+                ScrapeResult {
+                    headers: scraping.headers,
+                },
+                families,
+            ))
+        }
+    }))
+    .await
 }
@@ -3,4 +3,5 @@ pub mod client;
 pub mod config;
 pub mod metrics;
 pub mod proxy;
+pub mod scrape;
 pub mod server;
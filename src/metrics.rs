@@ -31,3 +31,73 @@ impl Default for CacheMetrics {
         Self::new()
     }
 }
+
+#[derive(Clone)]
+pub struct LimitMetrics {
+    pub dropped_samples: Counter<u64>,
+}
+
+impl LimitMetrics {
+    pub fn new() -> Self {
+        let meter = global::meter("axum-app");
+        let dropped_samples = meter
+            .u64_counter("proxy.limits.dropped_samples")
+            .with_description("Total number of samples dropped for exceeding limits")
+            .init();
+        LimitMetrics { dropped_samples }
+    }
+}
+
+impl Default for LimitMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamMetrics {
+    pub dropped_samples: Counter<u64>,
+}
+
+impl StreamMetrics {
+    pub fn new() -> Self {
+        let meter = global::meter("axum-app");
+        let dropped_samples = meter
+            .u64_counter("proxy.streaming.dropped_samples")
+            .with_description(
+                "Total number of samples dropped for belonging to a metric family already served",
+            )
+            .init();
+        StreamMetrics { dropped_samples }
+    }
+}
+
+impl Default for StreamMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct MergeMetrics {
+    pub failed_backends: Counter<u64>,
+}
+
+impl MergeMetrics {
+    pub fn new() -> Self {
+        let meter = global::meter("axum-app");
+        let failed_backends = meter
+            .u64_counter("proxy.merge.failed_backends")
+            .with_description(
+                "Total number of backend scrapes left out of partially served responses",
+            )
+            .init();
+        MergeMetrics { failed_backends }
+    }
+}
+
+impl Default for MergeMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,16 +1,19 @@
-use crate::config::HttpProxyTarget;
+use crate::config::{is_valid_metric_name, HttpProxyTarget, RequestHeaders, ResponseHeaders};
+use crate::metrics::{LimitMetrics, MergeMetrics, StreamMetrics};
+use crate::scrape::{Exemplar, Labels, Sample, Scrape, Value};
 use crate::{cache::SampleCacheStore, client, config};
 use axum::http;
 use axum::http::StatusCode;
 use hyper::body::Bytes;
 use itertools::Itertools;
-use prometheus_parse::{self, Sample};
+use opentelemetry::KeyValue;
+use prometheus::{proto, Encoder, ProtobufEncoder, PROTOBUF_FORMAT};
 use reqwest::header;
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::f64;
-use std::iter::zip;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Headers that must not be relayed from backend to client or vice versa.
 static HOPBYHOP: [&str; 8] = [
@@ -26,33 +29,48 @@ static HOPBYHOP: [&str; 8] = [
 // Headers that must be stripped from response of backend.
 static STRIP_FROM_RESPONSE: [&str; 1] = ["content-length"];
 
-// Headers that may be relayed from client to backend.
-static PROXIED_CLIENT_HEADERS: [&str; 1] = ["accept"];
-
-fn safely_clone_response_headers(orgheaders: header::HeaderMap) -> http::HeaderMap {
+fn safely_clone_response_headers(
+    orgheaders: header::HeaderMap,
+    policy: &ResponseHeaders,
+) -> http::HeaderMap {
     // println!("Original: {:?}", orgheaders);
     let mut headers = http::HeaderMap::new();
-    for (k, v) in orgheaders {
-        if let Some(kk) = k {
-            let lower = kk.to_string().to_lowercase();
-            if !HOPBYHOP.contains(&lower.as_str()) && !STRIP_FROM_RESPONSE.contains(&lower.as_str())
-            {
-                headers.insert(kk, v);
+    for (k, v) in orgheaders.iter() {
+        let lower = k.as_str();
+        if HOPBYHOP.contains(&lower)
+            || STRIP_FROM_RESPONSE.contains(&lower)
+            || policy.drop.iter().any(|d| d == lower)
+        {
+            continue;
+        }
+        if let Some(forward) = &policy.forward {
+            if !forward.iter().any(|f| f == lower) {
+                continue;
             }
         }
+        headers.append(k, v.clone());
     }
     // println!("Filtered: {:?}", headers);
     headers
 }
 
-fn safely_clone_request_headers(orgheaders: http::HeaderMap) -> header::HeaderMap {
+fn safely_clone_request_headers(
+    orgheaders: http::HeaderMap,
+    policy: &RequestHeaders,
+) -> header::HeaderMap {
     // println!("Original: {:?}", orgheaders);
     let mut headers = header::HeaderMap::new();
-    for (k, v) in orgheaders {
-        if let Some(kk) = k {
-            if PROXIED_CLIENT_HEADERS.contains(&kk.to_string().to_lowercase().as_str()) {
-                headers.insert(kk, v);
+    for (k, v) in orgheaders.iter() {
+        let lower = k.as_str();
+        if HOPBYHOP.contains(&lower) {
+            continue;
+        }
+        if let Some(renamed) = policy.rename.get(lower) {
+            if let Ok(kk) = header::HeaderName::from_bytes(renamed.as_bytes()) {
+                headers.append(kk, v.clone());
             }
+        } else if policy.forward.iter().any(|f| f == lower) {
+            headers.append(k, v.clone());
         }
     }
     // println!("Filtered: {:?}", headers);
@@ -65,10 +83,27 @@ fn fallback_headers() -> header::HeaderMap {
     fallback_headers
 }
 
-fn render_labels(labels: &prometheus_parse::Labels, extra: Option<String>) -> String {
+/// Escapes a label value as both exposition formats require.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escapes HELP text.  Unlike OpenMetrics, the Prometheus text format
+/// does not escape double quotes in HELP text.
+fn escape_help(help: &str, format: ExpositionFormat) -> String {
+    match format {
+        ExpositionFormat::OpenMetrics => escape_label_value(help),
+        _ => help.replace('\\', "\\\\").replace('\n', "\\n"),
+    }
+}
+
+fn render_labels(labels: &Labels, extra: Option<String>) -> String {
     let mut joined = labels
         .iter()
-        .map(|(n, v)| format!("{n}=\"{v}\""))
+        .map(|(n, v)| format!("{n}=\"{}\"", escape_label_value(v)))
         .collect::<Vec<String>>();
 
     joined.sort();
@@ -83,377 +118,3335 @@ fn render_labels(labels: &prometheus_parse::Labels, extra: Option<String>) -> St
     }
 }
 
-fn render_sample(sample: &prometheus_parse::Sample) -> Vec<String> {
-    let values = match &sample.value {
-        prometheus_parse::Value::Untyped(val)
-        | prometheus_parse::Value::Counter(val)
-        | prometheus_parse::Value::Gauge(val) => vec![format!("{:e}", val)],
-        prometheus_parse::Value::Histogram(val) => val
-            .iter()
-            .map(|h| format!("{:e}", h.count))
-            .collect::<Vec<String>>(),
-        prometheus_parse::Value::Summary(val) => val
-            .iter()
-            .map(|h| format!("{:e}", h.count))
-            .collect::<Vec<String>>(),
-    };
-    let labels = match &sample.value {
-        prometheus_parse::Value::Untyped(_val)
-        | prometheus_parse::Value::Counter(_val)
-        | prometheus_parse::Value::Gauge(_val) => vec![None],
-        prometheus_parse::Value::Histogram(val) => val
-            .iter()
-            .map(|h| {
-                Some(format!("le=\"{}\"", {
-                    if h.less_than == f64::INFINITY {
-                        "+Inf".to_string()
-                    } else if h.less_than == f64::NEG_INFINITY {
-                        "-Inf".to_string()
-                    } else {
-                        format!("{}", h.less_than)
-                    }
-                }))
-            })
-            .collect::<Vec<Option<String>>>(),
-        prometheus_parse::Value::Summary(val) => val
-            .iter()
-            .map(|h| Some(format!("quantile=\"{}\"", h.quantile)))
-            .collect::<Vec<Option<String>>>(),
+/// Formats a float the way the exposition formats expect.
+fn format_value(val: f64) -> String {
+    if val == f64::INFINITY {
+        "+Inf".to_string()
+    } else if val == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        format!("{:e}", val)
+    }
+}
+
+/// A line of a sample: suffix to the metric name, extra label,
+/// value and exemplar.
+type SampleLine<'a> = (&'static str, Option<String>, f64, Option<&'a Exemplar>);
+
+/// Renders the lines of a sample.  Suffixes follow the Prometheus
+/// conventions, and `_created` lines are not included.
+fn sample_lines(sample: &Sample) -> Vec<SampleLine<'_>> {
+    let mut lines = vec![];
+    let (sum, count) = match &sample.value {
+        Value::Counter(val) => {
+            lines.push(("", None, *val, sample.exemplar.as_ref()));
+            (None, None)
+        }
+        // OpenMetrics only allows exemplars on counters and buckets.
+        Value::Untyped(val) | Value::Gauge(val) => {
+            lines.push(("", None, *val, None));
+            (None, None)
+        }
+        Value::Histogram(h) => {
+            for bucket in &h.buckets {
+                let le = if bucket.less_than.is_infinite() {
+                    format_value(bucket.less_than)
+                } else {
+                    format!("{}", bucket.less_than)
+                };
+                lines.push((
+                    "_bucket",
+                    Some(format!("le=\"{le}\"")),
+                    bucket.count,
+                    bucket.exemplar.as_ref(),
+                ));
+            }
+            (h.sum, h.count)
+        }
+        Value::Summary(s) => {
+            for quantile in &s.quantiles {
+                let q = format!("quantile=\"{}\"", quantile.quantile);
+                lines.push(("", Some(q), quantile.count, None));
+            }
+            (s.sum, s.count)
+        }
     };
+    if let Some(sum) = sum {
+        lines.push(("_sum", None, sum, None));
+    }
+    if let Some(count) = count {
+        lines.push(("_count", None, count, None));
+    }
+    lines
+}
 
-    zip(values, labels)
-        .map(|(value, extra_label)| {
+fn render_sample(sample: &Sample) -> Vec<String> {
+    let timestamp = match sample.timestamp {
+        Some(timestamp) => format!(" {timestamp}"),
+        None => String::new(),
+    };
+    sample_lines(sample)
+        .into_iter()
+        .map(|(suffix, extra_label, value, _)| {
             format!(
-                "{}{} {}",
+                "{}{}{} {}{}",
                 sample.metric,
+                suffix,
                 render_labels(&sample.labels, extra_label),
-                value
+                format_value(value),
+                timestamp
             )
         })
         .collect::<Vec<String>>()
 }
 
-fn render_scrape_data(scrape: &prometheus_parse::Scrape) -> Bytes {
-    let mut help = scrape.docs.clone();
-    let rendered = scrape
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Untyped(_) => "untyped",
+        Value::Counter(_) => "counter",
+        Value::Gauge(_) => "gauge",
+        Value::Histogram(_) => "histogram",
+        Value::Summary(_) => "summary",
+    }
+}
+
+/// Returns the samples to be rendered, grouped in families of samples
+/// sharing the same metric name.  Families are either kept in the order
+/// the backend returned them in, or sorted by metric name.
+fn families_to_render(scrape: &Scrape, preserve_order: bool) -> Vec<Vec<&Sample>> {
+    let mut positions: HashMap<&String, usize> = HashMap::new();
+    for sample in &scrape.samples {
+        let position = positions.len();
+        positions.entry(&sample.metric).or_insert(position);
+    }
+    let mut families: Vec<Vec<&Sample>> = vec![];
+    for sample in scrape
         .samples
         .iter()
-        .sorted_by(|sample1, sample2| sample1.metric.cmp(&sample2.metric))
-        .map(|sample| {
-            (
-                &sample.metric,
-                &sample.value,
-                render_sample(sample).join("\n"),
-            )
-        })
-        .map(|(metric, value, rendered)| {
-            if let Some(h) = help.remove(metric) {
-                format!(
-                    "# HELP {} {}\n# TYPE {} {}\n{}",
-                    metric,
-                    h,
-                    metric,
-                    match value {
-                        prometheus_parse::Value::Untyped(_) => "untyped",
-                        prometheus_parse::Value::Counter(_) => "counter",
-                        prometheus_parse::Value::Gauge(_) => "gauge",
-                        prometheus_parse::Value::Histogram(_) => "histogram",
-                        prometheus_parse::Value::Summary(_) => "summary",
-                    },
-                    rendered
-                )
-            } else {
-                rendered
-            }
+        .sorted_by(|sample1, sample2| match preserve_order {
+            true => positions[&sample1.metric].cmp(&positions[&sample2.metric]),
+            false => sample1.metric.cmp(&sample2.metric),
         })
-        .collect::<Vec<String>>()
-        .join("\n")
-        + "\n";
-    Bytes::from(rendered)
-}
-
-#[derive(Clone)]
-/// The metrics proxy is in charge of receiving requests relayed by the server,
-/// contacting the backend via the scraper, and finally processing the response
-/// so that it complies with the policies defined in the configuration.
-pub struct MetricsProxier {
-    target: HttpProxyTarget,
-    cache: Arc<Mutex<SampleCacheStore>>,
-    client: reqwest::Client,
+        // Label rewriting may have collapsed different series onto the
+        // same metric name and label set.  Like Prometheus does with
+        // duplicate series, only the first one (in scrape order) is kept.
+        .unique_by(|sample| (&sample.metric, &sample.labels))
+    {
+        match families.last_mut() {
+            Some(family) if family[0].metric == sample.metric => family.push(sample),
+            _ => families.push(vec![sample]),
+        }
+    }
+    families
 }
 
-impl From<HttpProxyTarget> for MetricsProxier {
-    fn from(target: HttpProxyTarget) -> Self {
-        MetricsProxier {
-            target,
-            cache: Arc::new(Mutex::new(SampleCacheStore::default())),
-            client: reqwest::Client::new(),
+fn render_prometheus_family(samples: &[&Sample], scrape: &Scrape) -> String {
+    let metric = &samples[0].metric;
+    let mut rendered = String::new();
+    let help = scrape.docs.get(metric);
+    if let Some(help) = help {
+        rendered += &format!(
+            "# HELP {} {}\n",
+            metric,
+            escape_help(help, ExpositionFormat::Prometheus)
+        );
+    }
+    // Untyped families only get a type if they are documented.
+    if help.is_some() || !matches!(samples[0].value, Value::Untyped(_)) {
+        rendered += &format!("# TYPE {} {}\n", metric, type_name(&samples[0].value));
+    }
+    for sample in samples {
+        for line in render_sample(sample) {
+            rendered += &line;
+            rendered += "\n";
         }
     }
+    rendered
 }
 
-impl MetricsProxier {
-    pub async fn handle(&self, headers: http::HeaderMap) -> (StatusCode, http::HeaderMap, Bytes) {
-        let clientheaders = safely_clone_request_headers(headers);
-        let result =
-            client::scrape(self.client.clone(), &self.target.connect_to, clientheaders).await;
-        match result {
-            Err(error) => match error {
-                client::ScrapeError::Non200(non200) => (
-                    non200.status,
-                    safely_clone_response_headers(non200.headers),
-                    non200.data,
-                ),
-                client::ScrapeError::ParseError(parseerror) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    fallback_headers(),
-                    Bytes::from(format!("Error parsing output.\n\n{parseerror:#?}")),
-                ),
-                client::ScrapeError::DecodeError(decodeerror) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    fallback_headers(),
-                    Bytes::from(format!("Error decoding UTF-8 output.\n\n{decodeerror:#?}")),
-                ),
-                client::ScrapeError::FetchError(fetcherror) => {
-                    let mut statuscode = StatusCode::BAD_GATEWAY;
-                    let mut errmsg = format!("The target is down.\n\n{fetcherror:#?}");
-                    if fetcherror.is_timeout() {
-                        // 504 target timed out
-                        statuscode = StatusCode::GATEWAY_TIMEOUT;
-                        errmsg = format!("The target is timing out.\n\n{fetcherror:#?}");
+/// Renders samples in the OpenMetrics text format.  Counters are
+/// served under a family name without the `_total` suffix, which
+/// their samples always carry, and every family is typed.
+fn render_openmetrics_family(samples: &[&Sample], scrape: &Scrape) -> String {
+    let first = samples[0];
+    let family = match first.value {
+        Value::Counter(_) => first.metric.strip_suffix("_total"),
+        _ => None,
+    }
+    .unwrap_or(&first.metric);
+
+    let mut rendered = String::new();
+    let type_name = match first.value {
+        Value::Untyped(_) => "unknown",
+        _ => type_name(&first.value),
+    };
+    rendered += &format!("# TYPE {family} {type_name}\n");
+    if let Some(unit) = scrape.units.get(&first.metric) {
+        rendered += &format!("# UNIT {family} {unit}\n");
+    }
+    if let Some(help) = scrape.docs.get(&first.metric) {
+        let help = escape_help(help, ExpositionFormat::OpenMetrics);
+        rendered += &format!("# HELP {family} {help}\n");
+    }
+
+    for sample in samples {
+        // Timestamps are expressed in seconds in OpenMetrics.
+        let timestamp = match sample.timestamp {
+            Some(timestamp) => format!(" {}", timestamp as f64 / 1000.0),
+            None => String::new(),
+        };
+        for (suffix, extra_label, value, exemplar) in sample_lines(sample) {
+            // Counter samples carry the _total suffix in their name.
+            let suffix = match sample.value {
+                Value::Counter(_) => "_total",
+                _ => suffix,
+            };
+            let exemplar = match exemplar {
+                Some(exemplar) => format!(
+                    " # {} {}{}",
+                    render_labels(&exemplar.labels, None),
+                    format_value(exemplar.value),
+                    match exemplar.timestamp {
+                        Some(timestamp) => format!(" {timestamp}"),
+                        None => String::new(),
                     }
-                    (statuscode, fallback_headers(), Bytes::from(errmsg))
-                }
-            },
-            Ok(parsed) => (
-                StatusCode::OK,
-                safely_clone_response_headers(parsed.headers),
-                render_scrape_data(&self.apply_filters(parsed.series)),
-            ),
+                ),
+                None => String::new(),
+            };
+            rendered += &format!(
+                "{}{}{} {}{}{}\n",
+                family,
+                suffix,
+                render_labels(&sample.labels, extra_label),
+                format_value(value),
+                timestamp,
+                exemplar
+            );
+        }
+        if let Some(created) = sample.created {
+            rendered += &format!(
+                "{}_created{} {}{}\n",
+                family,
+                render_labels(&sample.labels, None),
+                format_value(created),
+                timestamp
+            );
         }
     }
+    rendered
+}
 
-    fn apply_filters(&self, series: prometheus_parse::Scrape) -> prometheus_parse::Scrape {
-        fn label_value(
-            metric: &String,
-            labels: &prometheus_parse::Labels,
-            label_name: &String,
-        ) -> String {
-            if label_name == "__name__" {
-                metric.to_string()
-            } else if labels.contains_key(label_name.as_str()) {
-                labels.get(label_name.as_str()).unwrap().to_string()
-            } else {
-                // No label with that name.  No match.
-                // This is consistent with how Prometheus metric relabeling
-                // deals with absent labels.
-                String::new()
+/// Renders samples as a length-delimited protocol buffer `MetricFamily`
+/// message, as understood by Prometheus.  The family is typed after its
+/// first sample.
+///
+/// # Errors
+/// Encoding fails for families without name, which neither the parser
+/// nor label filters produce.
+fn render_protobuf_family(samples: &[&Sample], scrape: &Scrape) -> prometheus::Result<Bytes> {
+    let first = samples[0];
+    let mut family = proto::MetricFamily::default();
+    family.set_name(first.metric.clone());
+    if let Some(help) = scrape.docs.get(&first.metric) {
+        family.set_help(help.clone());
+    }
+    family.set_field_type(match first.value {
+        Value::Counter(_) => proto::MetricType::COUNTER,
+        Value::Gauge(_) => proto::MetricType::GAUGE,
+        Value::Histogram(_) => proto::MetricType::HISTOGRAM,
+        Value::Summary(_) => proto::MetricType::SUMMARY,
+        Value::Untyped(_) => proto::MetricType::UNTYPED,
+    });
+    family.set_metric(
+        samples
+            .iter()
+            .map(|sample| protobuf_metric(sample))
+            .collect::<Vec<_>>()
+            .into(),
+    );
+
+    let mut rendered = vec![];
+    ProtobufEncoder::new().encode(&[family], &mut rendered)?;
+    Ok(Bytes::from(rendered))
+}
+
+fn protobuf_metric(sample: &Sample) -> proto::Metric {
+    let mut metric = proto::Metric::default();
+    metric.set_label(
+        sample
+            .labels
+            .iter()
+            .map(|(name, value)| {
+                let mut label = proto::LabelPair::default();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                label
+            })
+            .collect::<Vec<_>>()
+            .into(),
+    );
+    if let Some(timestamp) = sample.timestamp {
+        metric.set_timestamp_ms(timestamp);
+    }
+    match &sample.value {
+        Value::Counter(val) => metric.mut_counter().set_value(*val),
+        Value::Gauge(val) => metric.mut_gauge().set_value(*val),
+        Value::Untyped(val) => metric.mut_untyped().set_value(*val),
+        Value::Histogram(h) => {
+            let histogram = metric.mut_histogram();
+            for bucket in &h.buckets {
+                let mut b = proto::Bucket::default();
+                b.set_upper_bound(bucket.less_than);
+                b.set_cumulative_count(bucket.count as u64);
+                histogram.mut_bucket().push(b);
             }
+            // The count of a histogram is that of its +Inf bucket.
+            let count = h.count.or(h.buckets.last().map(|b| b.count));
+            histogram.set_sample_count(count.unwrap_or(0.0) as u64);
+            histogram.set_sample_sum(h.sum.unwrap_or(0.0));
         }
+        Value::Summary(s) => {
+            let summary = metric.mut_summary();
+            for quantile in &s.quantiles {
+                let mut q = proto::Quantile::default();
+                q.set_quantile(quantile.quantile);
+                q.set_value(quantile.count);
+                summary.mut_quantile().push(q);
+            }
+            summary.set_sample_count(s.count.unwrap_or(0.0) as u64);
+            summary.set_sample_sum(s.sum.unwrap_or(0.0));
+        }
+    }
+    metric
+}
 
-        let selectors = &self.target.label_filters;
-        let mut samples: Vec<prometheus_parse::Sample> = vec![];
-        let mut docs: HashMap<String, String> = HashMap::new();
-
-        {
-            let now = std::time::Instant::now();
-            let mut cache = self.cache.lock().unwrap();
+/// Formats a float for JSON output.  Like in the Prometheus HTTP API,
+/// values are strings, so that NaN and infinities can be represented.
+fn json_float(val: f64) -> String {
+    if val.is_nan() {
+        "NaN".to_string()
+    } else if val.is_infinite() {
+        format_value(val)
+    } else {
+        val.to_string()
+    }
+}
 
-            for sample in series.samples {
-                let mut keep: Option<bool> = None;
-                let mut cached_sample: Option<Sample> = None;
-                // The following value, if true at the end of this loop,
-                // indicates whether the sample should be cached for
-                // future lookups.  Values are only cached when the
-                // cache is consulted and the result is a cache miss.
-                let mut must_cache_sample = false;
-
-                for selector in selectors {
-                    let source_labels = &selector.source_labels;
-                    let label_values = source_labels
-                        .iter()
-                        .map(|label_name| label_value(&sample.metric, &sample.labels, label_name))
-                        .collect::<Vec<String>>()
-                        .join(selector.separator.as_str());
-                    for action in &selector.actions {
-                        if selector.regex.is_match(&label_values) {
-                            match action {
-                                config::LabelFilterAction::Keep => {
-                                    keep = Some(true);
-                                }
-                                config::LabelFilterAction::Drop => {
-                                    keep = Some(false);
-                                }
-                                config::LabelFilterAction::ReduceTimeResolution { resolution } => {
-                                    // If the cache has not expired according to the duration,
-                                    // then the cache returns the cached sample.
-                                    // Else, if the cache has expired according to the duration,
-                                    // then the cache returns nothing.
-                                    // Below, we insert it into the cache if nothing was returned
-                                    // into the cache at all.
-                                    let staleness: Duration = (*resolution).into();
-                                    cached_sample = cache.get(&sample, now, staleness);
-                                    must_cache_sample = true;
-                                }
-                            }
-                        }
-                    }
+/// Renders samples as a JSON object describing their family.
+fn render_json_family(samples: &[&Sample], scrape: &Scrape) -> String {
+    let first = samples[0];
+    let samples = samples
+        .iter()
+        .map(|sample| {
+            let value = match &sample.value {
+                Value::Untyped(val) | Value::Counter(val) | Value::Gauge(val) => {
+                    json!(json_float(*val))
                 }
+                Value::Histogram(h) => json!({
+                    "buckets": h
+                        .buckets
+                        .iter()
+                        .map(|b| json!({"le": json_float(b.less_than), "count": json_float(b.count)}))
+                        .collect::<Vec<_>>(),
+                    "sum": h.sum.map(json_float),
+                    "count": h.count.map(json_float),
+                }),
+                Value::Summary(s) => json!({
+                    "quantiles": s
+                        .quantiles
+                        .iter()
+                        .map(|q| json!({"quantile": json_float(q.quantile), "value": json_float(q.count)}))
+                        .collect::<Vec<_>>(),
+                    "sum": s.sum.map(json_float),
+                    "count": s.count.map(json_float),
+                }),
+            };
+            json!({
+                "labels": sample.labels,
+                "value": value,
+                "timestamp": sample.timestamp,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "name": first.metric,
+        "type": type_name(&first.value),
+        "help": scrape.docs.get(&first.metric),
+        "samples": samples,
+    })
+    .to_string()
+}
 
-                // Ignore this sample if the conclusion is that we were going to drop it anyway.
-                if let Some(trulykeep) = keep {
-                    if !trulykeep {
-                        continue;
-                    }
-                }
+/// Splits the value of an `Accept` header into media ranges, each one
+/// as its lowercase media type and its parameters.
+fn media_ranges(accept: &str) -> Vec<(String, HashMap<String, String>)> {
+    accept
+        .split(',')
+        .map(|media_range| {
+            let mut parts = media_range.split(';').map(|p| p.trim());
+            let media_type = parts.next().unwrap_or("").to_lowercase();
+            let params = parts
+                .filter_map(|param| param.split_once('='))
+                .map(|(name, value)| {
+                    (
+                        name.trim().to_lowercase(),
+                        value.trim().trim_matches('"').to_string(),
+                    )
+                })
+                .collect();
+            (media_type, params)
+        })
+        .collect()
+}
 
-                // Add this sample's metric name documentation if not yet added.
-                if !docs.contains_key(&sample.metric) && series.docs.contains_key(&sample.metric) {
-                    docs.insert(
-                        sample.metric.clone(),
-                        series.docs.get(&sample.metric).unwrap().clone(),
-                    );
-                }
+const PROTOBUF_MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+const JSON_MEDIA_TYPE: &str = "application/json";
 
-                if let Some(s) = cached_sample {
-                    samples.push(s);
-                } else {
-                    if must_cache_sample {
-                        cache.put(sample.clone(), now);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The formats the proxy can serve metrics in.
+enum ExpositionFormat {
+    Prometheus,
+    OpenMetrics,
+    Protobuf,
+    Json,
+}
+
+impl ExpositionFormat {
+    /// Picks the format preferred by the client according to its
+    /// `Accept` header, defaulting to the Prometheus text format.
+    fn negotiate(headers: &http::HeaderMap) -> Self {
+        let mut best = (ExpositionFormat::Prometheus, 0.0);
+        for accept in headers.get_all(header::ACCEPT) {
+            let Ok(accept) = accept.to_str() else {
+                continue;
+            };
+            for (media_type, params) in media_ranges(accept) {
+                let param = |name: &str| params.get(name).map(String::as_str);
+                let format = match media_type.as_str() {
+                    PROTOBUF_MEDIA_TYPE
+                        if param("proto") == Some("io.prometheus.client.MetricFamily")
+                            && param("encoding") == Some("delimited") =>
+                    {
+                        ExpositionFormat::Protobuf
                     }
-                    samples.push(sample);
+                    "application/openmetrics-text" => ExpositionFormat::OpenMetrics,
+                    JSON_MEDIA_TYPE => ExpositionFormat::Json,
+                    "text/plain" | "text/*" | "*/*" => ExpositionFormat::Prometheus,
+                    _ => continue,
+                };
+                let quality = param("q").map_or(1.0, |q| q.parse::<f64>().unwrap_or(0.0));
+                if quality > best.1 {
+                    best = (format, quality);
                 }
             }
         }
+        best.0
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            ExpositionFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+            ExpositionFormat::Protobuf => PROTOBUF_FORMAT,
+            ExpositionFormat::Json => JSON_MEDIA_TYPE,
+        }
+    }
+
+    /// Returns what is rendered before the first metric family, if anything.
+    fn header(&self) -> Option<Bytes> {
+        match self {
+            // A JSON array of families.
+            ExpositionFormat::Json => Some(Bytes::from("[")),
+            _ => None,
+        }
+    }
+
+    /// Returns what is rendered after the last metric family, if anything.
+    fn trailer(&self) -> Option<Bytes> {
+        match self {
+            ExpositionFormat::OpenMetrics => Some(Bytes::from("# EOF\n")),
+            ExpositionFormat::Json => Some(Bytes::from("]\n")),
+            _ => None,
+        }
+    }
+
+    /// Renders a single metric family, documented by `scrape`.
+    fn render_family(&self, family: &[&Sample], scrape: &Scrape, first: bool) -> Bytes {
+        match self {
+            ExpositionFormat::Prometheus => Bytes::from(render_prometheus_family(family, scrape)),
+            ExpositionFormat::OpenMetrics => Bytes::from(render_openmetrics_family(family, scrape)),
+            // Families that cannot be encoded are left out.
+            ExpositionFormat::Protobuf => {
+                render_protobuf_family(family, scrape).unwrap_or_default()
+            }
+            ExpositionFormat::Json => {
+                let separator = if first { "" } else { "," };
+                Bytes::from(separator.to_string() + &render_json_family(family, scrape))
+            }
+        }
+    }
+
+    /// Renders metrics one family at a time, so that they can be sent to
+    /// the client as they are rendered.
+    fn render_chunks<'a>(
+        &'a self,
+        scrape: &'a Scrape,
+        preserve_order: bool,
+    ) -> impl Iterator<Item = Bytes> + 'a {
+        let families = families_to_render(scrape, preserve_order)
+            .into_iter()
+            .enumerate()
+            .map(move |(index, family)| self.render_family(&family, scrape, index == 0));
+        self.header()
+            .into_iter()
+            .chain(families)
+            .chain(self.trailer())
+    }
 
-        prometheus_parse::Scrape { docs, samples }
+    #[cfg(test)]
+    fn render(&self, scrape: &Scrape) -> Bytes {
+        Bytes::from(
+            self.render_chunks(scrape, true)
+                .collect::<Vec<Bytes>>()
+                .concat(),
+        )
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::render_scrape_data;
-    use crate::config::{ConnectTo, HttpProxyTarget, LabelFilter};
-    use duration_string::DurationString;
-    use pretty_assertions::assert_eq as pretty_assert_eq;
-    use std::{str::FromStr, time::Duration};
+/// Removes from the `Accept` header relayed to the backend the formats
+/// the proxy cannot parse, so that the backend responds in text format.
+fn strip_unparseable_formats(headers: &mut header::HeaderMap) {
+    let accepted = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .filter(|media_range| {
+            media_ranges(media_range).iter().all(|(media_type, _)| {
+                media_type != PROTOBUF_MEDIA_TYPE && media_type != JSON_MEDIA_TYPE
+            })
+        })
+        .map(|media_range| media_range.trim())
+        .collect::<Vec<&str>>()
+        .join(",");
+    headers.remove(header::ACCEPT);
+    if let Ok(accepted) = header::HeaderValue::from_str(&accepted) {
+        if !accepted.is_empty() {
+            headers.insert(header::ACCEPT, accepted);
+        }
+    }
+}
 
-    fn make_test_proxy_target(filters: Vec<LabelFilter>) -> HttpProxyTarget {
-        HttpProxyTarget {
-            connect_to: ConnectTo {
-                url: url::Url::from_str("http://localhost:8080/metrics").unwrap(),
-                timeout: DurationString::new(Duration::new(5, 0)),
-            },
-            label_filters: filters,
-            cache_duration: DurationString::new(Duration::new(0, 0)),
+/// Accumulates the values of the samples aggregated into a single sample.
+struct Aggregation {
+    operation: config::AggregationOperation,
+    sum: f64,
+    min: f64,
+    max: f64,
+    count: u64,
+}
+
+impl Aggregation {
+    fn new(operation: config::AggregationOperation) -> Self {
+        Aggregation {
+            operation,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count: 0,
         }
     }
 
-    fn make_adapter_filter_tester(filters: Vec<LabelFilter>) -> crate::proxy::MetricsProxier {
-        crate::proxy::MetricsProxier::from(make_test_proxy_target(filters))
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
     }
 
-    struct TestPayload {
-        sorted_text: String,
-        parsed_scrape: prometheus_parse::Scrape,
+    /// Computes the aggregated value, typed according to the type of
+    /// the aggregated samples.  Only sums of counters remain counters.
+    fn value(&self, aggregated: &Value) -> Value {
+        let val = match self.operation {
+            config::AggregationOperation::Sum => self.sum,
+            config::AggregationOperation::Min => self.min,
+            config::AggregationOperation::Max => self.max,
+            config::AggregationOperation::Avg => self.sum / self.count as f64,
+            config::AggregationOperation::Count => self.count as f64,
+        };
+        match (aggregated, self.operation) {
+            (Value::Counter(_), config::AggregationOperation::Sum) => Value::Counter(val),
+            (Value::Untyped(_), config::AggregationOperation::Sum) => Value::Untyped(val),
+            _ => Value::Gauge(val),
+        }
     }
+}
 
-    impl TestPayload {
-        fn from_scrape(scrape: prometheus_parse::Scrape) -> Self {
-            let chunk = render_scrape_data(&scrape);
-            let rendered = std::str::from_utf8(chunk.as_ref()).unwrap();
-            let mut sorted_rendered: Vec<String> = rendered.lines().map(|s| s.to_owned()).collect();
-            sorted_rendered.sort();
-            let sorted_text = sorted_rendered.join("\n");
-            TestPayload {
-                sorted_text,
-                parsed_scrape: scrape,
-            }
+/// Aggregation groups are keyed by the position of the aggregate
+/// action, metric name and grouping labels.
+type GroupKey = ((usize, usize), String, Labels);
+
+/// Filtering state of a scrape, carried across the metric families
+/// filtered as they arrive from the backend.
+struct FilterState {
+    samples: Vec<Sample>,
+    // Aggregated samples are placed where the first sample of their
+    // group was found, and their values are computed once all samples
+    // have been processed.
+    groups: HashMap<GroupKey, (usize, Aggregation)>,
+    docs: HashMap<String, String>,
+    units: HashMap<String, String>,
+    // Number of time series served so far, as limited by `sample_limit`.
+    series_count: usize,
+    // Metric families already sent to the client, when the filtered
+    // families are served as they are received.
+    served: HashSet<String>,
+    now: std::time::Instant,
+    fetched_at: i64,
+}
+
+impl FilterState {
+    fn new() -> Self {
+        FilterState {
+            samples: vec![],
+            groups: HashMap::new(),
+            docs: HashMap::new(),
+            units: HashMap::new(),
+            series_count: 0,
+            served: HashSet::new(),
+            now: std::time::Instant::now(),
+            fetched_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as i64)
+                .unwrap_or_default(),
         }
+    }
 
-        fn from_text(text: &str) -> Self {
-            let parsed_scrape =
-                prometheus_parse::Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
-            TestPayload::from_scrape(parsed_scrape)
+    /// Computes the values of aggregated samples and takes the samples
+    /// filtered so far, along with their documentation.  Samples filtered
+    /// afterwards are not aggregated with those taken.
+    fn take(&mut self) -> Scrape {
+        for (index, aggregation) in self.groups.values() {
+            self.samples[*index].value = aggregation.value(&self.samples[*index].value);
+        }
+        self.groups.clear();
+        let samples = std::mem::take(&mut self.samples);
+        let documented = |documentation: &HashMap<String, String>| {
+            samples
+                .iter()
+                .filter_map(|sample| documentation.get_key_value(&sample.metric))
+                .map(|(metric, text)| (metric.clone(), text.clone()))
+                .collect()
+        };
+        Scrape {
+            docs: documented(&self.docs),
+            units: documented(&self.units),
+            samples,
         }
     }
 
-    #[test]
-    fn test_proxy_no_filtering() {
-        let adapter = make_adapter_filter_tester(vec![]);
-        let text = r#"
-# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
-# TYPE node_softnet_times_squeezed_total counter
-node_softnet_times_squeezed_total{cpu="0"} 0
-node_softnet_times_squeezed_total{cpu="1"} 0
-node_softnet_times_squeezed_total{cpu="10"} 0
-node_softnet_times_squeezed_total{cpu="11"} 0
-node_softnet_times_squeezed_total{cpu="12"} 0
-node_softnet_times_squeezed_total{cpu="13"} 0
-node_softnet_times_squeezed_total{cpu="14"} 0
-node_softnet_times_squeezed_total{cpu="15"} 0
-node_softnet_times_squeezed_total{cpu="2"} 0
-node_softnet_times_squeezed_total{cpu="3"} 0
-node_softnet_times_squeezed_total{cpu="4"} 0
-node_softnet_times_squeezed_total{cpu="5"} 0
-node_softnet_times_squeezed_total{cpu="6"} 0
-node_softnet_times_squeezed_total{cpu="7"} 0
-node_softnet_times_squeezed_total{cpu="8"} 0
-node_softnet_times_squeezed_total{cpu="9"} 0
-"#;
-        let inp_ = TestPayload::from_text(text);
-        let exp_ = TestPayload::from_text(text);
-        let filtered = adapter.apply_filters(inp_.parsed_scrape);
-        let out_ = TestPayload::from_scrape(filtered);
-        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    /// Computes the values of aggregated samples and returns the
+    /// filtered scrape.
+    fn finish(mut self) -> Scrape {
+        self.take()
     }
+}
 
-    #[test]
-    fn test_proxy_one_label_filtering() {
-        let adapter = make_adapter_filter_tester(
-            serde_yaml::from_str(
-                r#"
-- regex: node_softnet_times_squeezed_total
-  actions: [drop]
-- source_labels: [cpu]
-  regex: "1"
-  actions: [keep]
-"#,
+/// Adds a label to a sample, resolving conflicts with a label of the
+/// same name already present in the sample according to `on_conflict`.
+fn add_label(
+    sample: &mut Sample,
+    label_name: &str,
+    value: &str,
+    on_conflict: config::LabelConflictPolicy,
+) {
+    if let Some(existing) = sample.labels.get(label_name) {
+        match on_conflict {
+            config::LabelConflictPolicy::Overwrite => {}
+            config::LabelConflictPolicy::Keep => return,
+            config::LabelConflictPolicy::Rename => {
+                // Prefix repeatedly until the name is free, as Prometheus does.
+                let mut exported = format!("exported_{label_name}");
+                while sample.labels.contains_key(&exported) {
+                    exported = format!("exported_{exported}");
+                }
+                let existing = existing.clone();
+                sample.labels.insert(exported, existing);
+            }
+        }
+    }
+    sample
+        .labels
+        .insert(label_name.to_string(), value.to_string());
+}
+
+/// Merges the metric families returned by several backends, in the
+/// order the backends are listed in.  When more than one backend returns
+/// the same time series, or metric families of different types under the
+/// same name, the backend taking precedence according to `on_conflict`
+/// is served.  Documentation and units are served from a backend
+/// returning the metric with the type it is served with.
+fn merge_families(
+    backends: Vec<Vec<Scrape>>,
+    on_conflict: config::MergeConflictPolicy,
+) -> Result<Vec<Scrape>, String> {
+    let precedence: Vec<usize> = match on_conflict {
+        config::MergeConflictPolicy::Last => (0..backends.len()).rev().collect(),
+        _ => (0..backends.len()).collect(),
+    };
+    let mut kept: Vec<Vec<Vec<bool>>> = vec![];
+    let mut docs_owners: HashMap<String, usize> = HashMap::new();
+    let mut units_owners: HashMap<String, usize> = HashMap::new();
+    {
+        // Series and family types are claimed by the first backend (in
+        // order of precedence) returning them.
+        let mut series_owners: HashMap<(&String, &Labels), usize> = HashMap::new();
+        let mut type_owners: HashMap<&String, (std::mem::Discriminant<Value>, usize)> =
+            HashMap::new();
+        kept.resize(backends.len(), vec![]);
+        for &backend in &precedence {
+            for family in &backends[backend] {
+                let mut kept_samples = vec![];
+                for sample in &family.samples {
+                    let kind = std::mem::discriminant(&sample.value);
+                    let (owner_kind, owner) =
+                        *type_owners.entry(&sample.metric).or_insert((kind, backend));
+                    let conflict = if owner != backend && owner_kind != kind {
+                        Some(format!(
+                            "metric {} has a different type in backend {} than in backend {}",
+                            sample.metric,
+                            owner + 1,
+                            backend + 1
+                        ))
+                    } else {
+                        let owner = *series_owners
+                            .entry((&sample.metric, &sample.labels))
+                            .or_insert(backend);
+                        (owner != backend).then(|| {
+                            format!(
+                                "time series {} {:?} is returned by backends {} and {}",
+                                sample.metric,
+                                sample.labels,
+                                owner + 1,
+                                backend + 1
+                            )
+                        })
+                    };
+                    match conflict {
+                        Some(errmsg) if on_conflict == config::MergeConflictPolicy::Fail => {
+                            return Err(errmsg)
+                        }
+                        Some(_) => kept_samples.push(false),
+                        None => kept_samples.push(true),
+                    }
+                }
+                kept[backend].push(kept_samples);
+            }
+        }
+        // Documentation and units are claimed by the first backend
+        // returning the metric with the type it is served with, so that
+        // they describe the samples served.
+        let of_served_type = |family: &Scrape, metric: &String| match type_owners.get(metric) {
+            Some((owner_kind, _)) => family.samples.iter().any(|sample| {
+                &sample.metric == metric && std::mem::discriminant(&sample.value) == *owner_kind
+            }),
+            None => true,
+        };
+        for &backend in &precedence {
+            for family in &backends[backend] {
+                for metric in family.docs.keys() {
+                    if of_served_type(family, metric) {
+                        docs_owners.entry(metric.clone()).or_insert(backend);
+                    }
+                }
+                for metric in family.units.keys() {
+                    if of_served_type(family, metric) {
+                        units_owners.entry(metric.clone()).or_insert(backend);
+                    }
+                }
+            }
+        }
+    }
+    let mut merged = vec![];
+    for (backend, (families, kept)) in backends.into_iter().zip(kept).enumerate() {
+        for (family, kept) in families.into_iter().zip(kept) {
+            merged.push(Scrape {
+                docs: family
+                    .docs
+                    .into_iter()
+                    .filter(|(metric, _)| docs_owners.get(metric) == Some(&backend))
+                    .collect(),
+                units: family
+                    .units
+                    .into_iter()
+                    .filter(|(metric, _)| units_owners.get(metric) == Some(&backend))
+                    .collect(),
+                samples: family
+                    .samples
+                    .into_iter()
+                    .zip(kept)
+                    .filter_map(|(sample, kept)| kept.then_some(sample))
+                    .collect(),
+            });
+        }
+    }
+    Ok(merged)
+}
+
+#[derive(Clone)]
+/// The metrics proxy is in charge of receiving requests relayed by the server,
+/// contacting the backend via the scraper, and finally processing the response
+/// so that it complies with the policies defined in the configuration.
+pub struct MetricsProxier {
+    target: HttpProxyTarget,
+    cache: Arc<Mutex<SampleCacheStore>>,
+    /// One client per backend, in the order backends are listed in.
+    clients: Vec<reqwest::Client>,
+    metrics: LimitMetrics,
+    stream_metrics: StreamMetrics,
+    merge_metrics: MergeMetrics,
+}
+
+impl From<HttpProxyTarget> for MetricsProxier {
+    fn from(target: HttpProxyTarget) -> Self {
+        // Each backend gets its own client, configured with its TLS settings.
+        let clients = target
+            .connect_to
+            .iter()
+            .map(|connect_to| match &connect_to.tls_client_config {
+                Some(tls) => reqwest::Client::builder()
+                    .use_preconfigured_tls(tls.clone())
+                    .build()
+                    .expect("TLS settings are validated when loading the configuration"),
+                None => reqwest::Client::new(),
+            })
+            .collect();
+        MetricsProxier {
+            target,
+            cache: Arc::new(Mutex::new(SampleCacheStore::default())),
+            clients,
+            metrics: LimitMetrics::default(),
+            stream_metrics: StreamMetrics::default(),
+            merge_metrics: MergeMetrics::default(),
+        }
+    }
+}
+
+impl MetricsProxier {
+    pub async fn handle(
+        &self,
+        headers: http::HeaderMap,
+        query: HashMap<String, String>,
+    ) -> (StatusCode, http::HeaderMap, hyper::Body) {
+        let shards = match self.requested_shards(&query) {
+            Ok(shards) => shards,
+            Err(errmsg) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    fallback_headers(),
+                    hyper::Body::from(errmsg),
+                )
+            }
+        };
+        let backend_query = match self.backend_query(&query) {
+            Ok(backend_query) => backend_query,
+            Err(errmsg) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    fallback_headers(),
+                    hyper::Body::from(errmsg),
+                )
+            }
+        };
+        let format = ExpositionFormat::negotiate(&headers);
+        let mut clientheaders = safely_clone_request_headers(headers, &self.target.request_headers);
+        strip_unparseable_formats(&mut clientheaders);
+        // Scrapes failing for exceeding limits must fail with an error
+        // status, which cannot be sent once the response has started,
+        // so they are not streamed.  Their size is bounded by the limits.
+        if self.target.connect_to.len() == 1
+            && self.target.preserve_order
+            && !self.target.limits.can_fail()
+        {
+            return self
+                .stream(clientheaders, &backend_query, shards, format)
+                .await;
+        }
+
+        // Metric families of several backends are merged, and sorting
+        // metric families needs all of them, so the filtered scrape is
+        // only rendered once the backends have responded in full.  So is
+        // a scrape that may fail for exceeding limits.
+        let (scraped, families) = match self.scrape_backends(clientheaders, &backend_query).await {
+            Ok(result) => result,
+            Err(error) => return self.error_response(error),
+        };
+        let mut state = FilterState::new();
+        let mut result = Ok(scraped);
+        for family in families {
+            if let Err(errmsg) = self.filter_family(&mut state, family, &shards) {
+                result = Err(client::ScrapeError::LimitError(errmsg));
+                break;
+            }
+        }
+        match result {
+            Err(error) => self.error_response(error),
+            Ok(scraped) => {
+                let filtered = state.finish();
+                // Render one metric family at a time into the response
+                // body, rather than rendering the whole response first.
+                let preserve_order = self.target.preserve_order;
+                let (mut sender, body) = hyper::Body::channel();
+                tokio::spawn(async move {
+                    for chunk in format.render_chunks(&filtered, preserve_order) {
+                        if sender.send_data(chunk).await.is_err() {
+                            // The client went away.
+                            break;
+                        }
+                    }
+                });
+                (
+                    StatusCode::OK,
+                    self.response_headers(scraped.headers, format),
+                    body,
+                )
+            }
+        }
+    }
+
+    /// Scrapes the only backend, sending each metric family to the client
+    /// as soon as it has been received and filtered, so the response is
+    /// never held in memory as a whole.  Since the response status has
+    /// been sent by then, malformed lines found after the first metric
+    /// family abort the response instead of causing an error status.
+    async fn stream(
+        &self,
+        clientheaders: header::HeaderMap,
+        backend_query: &[(String, String)],
+        shards: HashMap<String, u64>,
+        format: ExpositionFormat,
+    ) -> (StatusCode, http::HeaderMap, hyper::Body) {
+        let mut scraping = match client::scrape(
+            self.clients[0].clone(),
+            &self.target.connect_to[0],
+            clientheaders,
+            backend_query,
+        )
+        .await
+        {
+            Ok(scraping) => scraping,
+            Err(error) => return self.error_response(error),
+        };
+        let mut state = FilterState::new();
+        let first = match self
+            .next_chunk(&mut scraping, &mut state, &shards, format)
+            .await
+        {
+            Ok(first) => first,
+            Err(error) => return self.error_response(error),
+        };
+        let headers = self.response_headers(scraping.headers.clone(), format);
+        let proxy = self.clone();
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut next = Ok(first);
+            if let Some(header) = format.header() {
+                if sender.send_data(header).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                match next {
+                    Ok(Some(chunk)) => {
+                        if sender.send_data(chunk).await.is_err() {
+                            // The client went away.
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        // An incomplete response must not pass for a
+                        // complete one.
+                        sender.abort();
+                        return;
+                    }
+                }
+                next = proxy
+                    .next_chunk(&mut scraping, &mut state, &shards, format)
+                    .await;
+            }
+            if let Some(trailer) = format.trailer() {
+                let _ = sender.send_data(trailer).await;
+            }
+        });
+        (StatusCode::OK, headers, body)
+    }
+
+    /// Receives, filters and renders the next metric family of a scrape
+    /// being streamed, or returns `None` once the scrape is over.
+    async fn next_chunk(
+        &self,
+        scraping: &mut client::Scraping,
+        state: &mut FilterState,
+        shards: &HashMap<String, u64>,
+        format: ExpositionFormat,
+    ) -> Result<Option<Bytes>, client::ScrapeError> {
+        let Some(mut family) = scraping.next_family().await? else {
+            return Ok(None);
+        };
+        self.add_backend_labels(&self.target.connect_to[0], &mut family);
+        self.filter_family(state, family, shards)
+            .map_err(client::ScrapeError::LimitError)?;
+        let filtered = state.take();
+        let mut chunk = vec![];
+        let mut dropped = 0;
+        for family in families_to_render(&filtered, true) {
+            // A metric family cannot be served twice, so samples that
+            // relabeling moved into a family served already are dropped.
+            if !state.served.insert(family[0].metric.clone()) {
+                dropped += family
+                    .iter()
+                    .map(|sample| sample.series_count())
+                    .sum::<usize>();
+                continue;
+            }
+            let first = state.served.len() == 1;
+            chunk.extend_from_slice(&format.render_family(&family, &filtered, first));
+        }
+        if dropped > 0 {
+            self.stream_metrics.dropped_samples.add(
+                dropped as u64,
+                &[KeyValue::new(
+                    "backend",
+                    self.target.connect_to[0].url.to_string(),
+                )],
+            );
+        }
+        Ok(Some(Bytes::from(chunk)))
+    }
+
+    /// Returns the headers of a successful response, relayed from the
+    /// backend response according to the configured policy.
+    fn response_headers(
+        &self,
+        backend_headers: header::HeaderMap,
+        format: ExpositionFormat,
+    ) -> http::HeaderMap {
+        // The backend may have responded in a different format
+        // than the one the metrics are rendered in.
+        let mut headers =
+            safely_clone_response_headers(backend_headers, &self.target.response_headers);
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(format.content_type()),
+        );
+        headers
+    }
+
+    /// Returns the response describing a failed scrape.
+    fn error_response(
+        &self,
+        error: client::ScrapeError,
+    ) -> (StatusCode, http::HeaderMap, hyper::Body) {
+        match error {
+            client::ScrapeError::Non200(non200) => (
+                non200.status,
+                safely_clone_response_headers(non200.headers, &self.target.response_headers),
+                hyper::Body::from(non200.data),
+            ),
+            client::ScrapeError::ParseError(parseerror) => (
+                StatusCode::BAD_GATEWAY,
+                fallback_headers(),
+                hyper::Body::from(format!(
+                    "The target returned malformed metrics.\n\n{parseerror}"
+                )),
+            ),
+            client::ScrapeError::DecodeError(decodeerror) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                fallback_headers(),
+                hyper::Body::from(format!("Error decoding UTF-8 output.\n\n{decodeerror:#?}")),
+            ),
+            client::ScrapeError::ConflictError(conflicterror) => (
+                StatusCode::BAD_GATEWAY,
+                fallback_headers(),
+                hyper::Body::from(format!(
+                    "The targets returned conflicting metrics.\n\n{conflicterror}"
+                )),
+            ),
+            client::ScrapeError::LimitError(errmsg) => (
+                StatusCode::BAD_GATEWAY,
+                fallback_headers(),
+                hyper::Body::from(format!(
+                    "The target exceeded the configured limits.\n\n{errmsg}"
+                )),
+            ),
+            client::ScrapeError::CredentialsError(credentialserror) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                fallback_headers(),
+                hyper::Body::from(format!(
+                    "Error reading the credentials of the target.\n\n{credentialserror:#?}"
+                )),
+            ),
+            client::ScrapeError::FetchError(fetcherror) => {
+                let mut statuscode = StatusCode::BAD_GATEWAY;
+                let mut errmsg = format!("The target is down.\n\n{fetcherror:#?}");
+                if fetcherror.is_timeout() {
+                    // 504 target timed out
+                    statuscode = StatusCode::GATEWAY_TIMEOUT;
+                    errmsg = format!("The target is timing out.\n\n{fetcherror:#?}");
+                }
+                (statuscode, fallback_headers(), hyper::Body::from(errmsg))
+            }
+        }
+    }
+
+    /// Adds the labels configured for a backend to the metrics it returned.
+    fn add_backend_labels(&self, connect_to: &config::ConnectTo, family: &mut Scrape) {
+        for sample in &mut family.samples {
+            for (label_name, value) in &connect_to.labels {
+                add_label(
+                    sample,
+                    label_name,
+                    value,
+                    self.target.extra_labels_on_conflict,
+                );
+            }
+        }
+    }
+
+    /// Scrapes all backends concurrently, and merges the metric families
+    /// they returned.  The response headers of the first backend scraped
+    /// successfully are returned along with the merged metric families.
+    async fn scrape_backends(
+        &self,
+        clientheaders: header::HeaderMap,
+        backend_query: &[(String, String)],
+    ) -> Result<(client::ScrapeResult, Vec<Scrape>), client::ScrapeError> {
+        let targets: Vec<_> = self
+            .clients
+            .iter()
+            .cloned()
+            .zip(self.target.connect_to.iter())
+            .collect();
+        let mut first_scraped = None;
+        let mut errors = vec![];
+        let mut backends = vec![];
+        for (result, connect_to) in client::scrape_all(&targets, clientheaders, backend_query)
+            .await
+            .into_iter()
+            .zip(&self.target.connect_to)
+        {
+            match result {
+                Ok((scraped, mut families)) => {
+                    for family in &mut families {
+                        self.add_backend_labels(connect_to, family);
+                    }
+                    first_scraped.get_or_insert(scraped);
+                    backends.push(families);
+                }
+                Err(error) => errors.push((connect_to, error)),
+            }
+        }
+        let scraped = match first_scraped {
+            Some(scraped) if errors.is_empty() => scraped,
+            Some(scraped)
+                if self.target.merge.on_failure == config::BackendFailurePolicy::ServePartial =>
+            {
+                // The failed backends are left out of the response, so
+                // they are reported here instead.
+                for (connect_to, error) in errors {
+                    eprintln!(
+                        "Serving partial metrics without backend {}: {:?}",
+                        connect_to.url, error
+                    );
+                    self.merge_metrics
+                        .failed_backends
+                        .add(1, &[KeyValue::new("backend", connect_to.url.to_string())]);
+                }
+                scraped
+            }
+            _ => match errors.into_iter().next() {
+                Some((_, error)) => return Err(error),
+                None => unreachable!("there is at least one backend"),
+            },
+        };
+        match merge_families(backends, self.target.merge.on_conflict) {
+            Ok(families) => Ok((scraped, families)),
+            Err(errmsg) => Err(client::ScrapeError::ConflictError(errmsg)),
+        }
+    }
+
+    /// Checks a filtered sample against the configured limits, given the
+    /// number of time series already served.  Returns a message describing
+    /// the first limit exceeded, if any.
+    fn limit_violation(&self, sample: &Sample, total: usize) -> Option<String> {
+        let limits = &self.target.limits;
+        let label_count = sample.labels.len() + 1;
+        if limits.label_limit.is_some_and(|l| label_count > l) {
+            Some(format!(
+                "{} has {label_count} labels, exceeding label_limit {}",
+                sample.metric,
+                limits.label_limit.unwrap()
+            ))
+        } else if let Some((name, _)) = sample.labels.iter().find(|(name, _)| {
+            limits
+                .label_name_length_limit
+                .is_some_and(|l| name.len() > l)
+        }) {
+            Some(format!(
+                "{} has label name {name} exceeding label_name_length_limit {}",
+                sample.metric,
+                limits.label_name_length_limit.unwrap()
+            ))
+        } else if let Some(value) = std::iter::once(&sample.metric)
+            .chain(sample.labels.values())
+            .find(|value| {
+                limits
+                    .label_value_length_limit
+                    .is_some_and(|l| value.len() > l)
+            })
+        {
+            Some(format!(
+                "{} has label value {value:?} exceeding label_value_length_limit {}",
+                sample.metric,
+                limits.label_value_length_limit.unwrap()
+            ))
+        } else if limits
+            .sample_limit
+            .is_some_and(|l| total + sample.series_count() > l)
+        {
+            Some(format!(
+                "more than {} samples were returned, exceeding sample_limit",
+                limits.sample_limit.unwrap()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Counts time series dropped for exceeding the configured limits.
+    fn count_dropped(&self, dropped: usize) {
+        self.metrics.dropped_samples.add(
+            dropped as u64,
+            &[KeyValue::new(
+                "backend",
+                self.target
+                    .connect_to
+                    .iter()
+                    .map(|connect_to| connect_to.url.as_str())
+                    .join(","),
+            )],
+        );
+    }
+
+    /// Returns the shard requested by the client for every query parameter
+    /// designated by a `hashmod` action, keyed by query parameter name.
+    fn requested_shards(
+        &self,
+        query: &HashMap<String, String>,
+    ) -> Result<HashMap<String, u64>, String> {
+        let mut shards = HashMap::new();
+        for selector in &self.target.label_filters {
+            for action in &selector.actions {
+                if let config::LabelFilterAction::HashMod {
+                    modulus,
+                    query_parameter: Some(param),
+                    ..
+                } = action
+                {
+                    if let Some(value) = query.get(param) {
+                        match value.parse::<u64>() {
+                            Ok(shard) if shard < *modulus => {
+                                shards.insert(param.clone(), shard);
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "Query parameter {param} must be a number between 0 and {}.",
+                                    modulus - 1
+                                ))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(shards)
+    }
+
+    /// Returns the query parameters to send to the backend: those of
+    /// the client request allowed to be relayed, in configuration order,
+    /// followed by the fixed ones, sorted by name.
+    fn backend_query(
+        &self,
+        query: &HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>, String> {
+        let policy = &self.target.query_parameters;
+        let mut backend_query = vec![];
+        for name in &policy.forward {
+            if let Some(value) = query.get(name) {
+                if let Some(regex) = policy.validate.get(name) {
+                    if !regex.is_match(value) {
+                        return Err(format!(
+                            "Query parameter {name} must match {}.",
+                            regex.as_str()
+                        ));
+                    }
+                }
+                backend_query.push((name.clone(), value.clone()));
+            }
+        }
+        backend_query.extend(
+            policy
+                .set
+                .iter()
+                .sorted()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        Ok(backend_query)
+    }
+
+    #[cfg(test)]
+    fn apply_filters(&self, series: Scrape, shards: &HashMap<String, u64>) -> Scrape {
+        let mut state = FilterState::new();
+        self.filter_family(&mut state, series, shards).unwrap();
+        state.finish()
+    }
+
+    /// Filters a batch of samples -- usually a single metric family,
+    /// as the scraper hands them over -- accumulating the result into
+    /// the filtering state of the scrape being processed.  The configured
+    /// limits are enforced on the samples as they are filtered; depending
+    /// on configuration, samples exceeding the limits are either dropped,
+    /// or cause an error describing the first limit exceeded.
+    fn filter_family(
+        &self,
+        state: &mut FilterState,
+        series: Scrape,
+        shards: &HashMap<String, u64>,
+    ) -> Result<(), String> {
+        fn label_value(metric: &String, labels: &Labels, label_name: &String) -> String {
+            if label_name == "__name__" {
+                metric.to_string()
+            } else if labels.contains_key(label_name.as_str()) {
+                labels.get(label_name.as_str()).unwrap().to_string()
+            } else {
+                // No label with that name.  No match.
+                // This is consistent with how Prometheus metric relabeling
+                // deals with absent labels.
+                String::new()
+            }
+        }
+
+        fn set_label_value(sample: &mut Sample, label_name: &String, value: String) {
+            if label_name == "__name__" {
+                // Metrics cannot be served under an invalid name, so
+                // they keep their name instead, as in Prometheus.
+                if is_valid_metric_name(&value) {
+                    sample.metric = value;
+                }
+            } else if value.is_empty() {
+                // Like in Prometheus metric relabeling, setting a label
+                // to the empty string is the same as removing it.
+                sample.labels.remove(label_name);
+            } else {
+                sample.labels.insert(label_name.to_string(), value);
+            }
+        }
+
+        fn strip_exemplars(sample: &mut Sample) {
+            sample.exemplar = None;
+            if let Value::Histogram(h) = &mut sample.value {
+                for bucket in &mut h.buckets {
+                    bucket.exemplar = None;
+                }
+            }
+        }
+
+        fn transform_value(sample: &mut Sample, transform: impl Fn(f64) -> f64) {
+            // Histograms and summaries are left untouched, since their
+            // bucket and quantile counts are not measurements that can
+            // be transformed without making the metric inconsistent.
+            match &mut sample.value {
+                Value::Untyped(val) | Value::Counter(val) | Value::Gauge(val) => {
+                    *val = transform(*val)
+                }
+                Value::Histogram(_) | Value::Summary(_) => {}
+            }
+        }
+
+        let selectors = &self.target.label_filters;
+        let now = state.now;
+        let fetched_at = state.fetched_at;
+        let samples = &mut state.samples;
+        let groups = &mut state.groups;
+        let docs = &mut state.docs;
+        let units = &mut state.units;
+        let series_count = &mut state.series_count;
+        let truncate =
+            self.target.limits.on_limit_exceeded == config::LimitExceededAction::Truncate;
+        let mut dropped = 0;
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+
+            for mut sample in series.samples {
+                let mut keep: Option<bool> = None;
+                // Samples of another shard than the one requested are
+                // dropped regardless of later `keep` actions, so that
+                // shards remain disjoint.
+                let mut other_shard = false;
+                let mut aggregate: Option<((usize, usize), &config::LabelFilterAction)> = None;
+                // Samples missing from the cache of a `reduce_time_resolution`
+                // action, as they were when the action was taken.  They are
+                // only cached if the sample is finally kept.
+                let mut uncached: Vec<Sample> = vec![];
+                // Actions may rename the metric, but its documentation
+                // is keyed by the name the backend gave it.
+                let original_metric = sample.metric.clone();
+
+                // Labels added to every sample are added before filtering,
+                // so that filters can match on them.
+                for (label_name, value) in &self.target.extra_labels {
+                    add_label(
+                        &mut sample,
+                        label_name,
+                        value,
+                        self.target.extra_labels_on_conflict,
+                    );
+                }
+
+                for (selector_index, selector) in selectors.iter().enumerate() {
+                    let source_labels = &selector.source_labels;
+                    let label_values = source_labels
+                        .iter()
+                        .map(|label_name| label_value(&sample.metric, &sample.labels, label_name))
+                        .collect::<Vec<String>>()
+                        .join(selector.separator.as_str());
+                    // Matching is decided before any action runs, so that actions
+                    // transforming the value do not affect subsequent actions.
+                    let matches = selector.regex.is_match(&label_values)
+                        && selector.value_matches(&sample.value);
+                    for (action_index, action) in selector.actions.iter().enumerate() {
+                        if matches {
+                            match action {
+                                config::LabelFilterAction::Keep => {
+                                    keep = Some(true);
+                                }
+                                config::LabelFilterAction::Drop => {
+                                    keep = Some(false);
+                                }
+                                config::LabelFilterAction::ReduceTimeResolution { resolution } => {
+                                    // If the cache has not expired according to the duration,
+                                    // then the cache returns the cached sample, which replaces
+                                    // the sample being processed from here on.
+                                    // Else, if the cache has expired according to the duration,
+                                    // then the cache returns nothing, and the sample (as it is
+                                    // at this point of processing) is inserted into the cache
+                                    // below, unless it is dropped.
+                                    // Values are only cached when the cache is consulted and
+                                    // the result is a cache miss.
+                                    let staleness: Duration = (*resolution).into();
+                                    match cache.get(&sample, now, staleness) {
+                                        Some(cached_sample) => sample = cached_sample,
+                                        None => {
+                                            let mut cached_sample = sample.clone();
+                                            if self.target.timestamps
+                                                == config::TimestampPolicy::StampCached
+                                            {
+                                                cached_sample.timestamp =
+                                                    cached_sample.timestamp.or(Some(fetched_at));
+                                            }
+                                            uncached.push(cached_sample);
+                                        }
+                                    }
+                                }
+                                config::LabelFilterAction::Replace {
+                                    target_label,
+                                    replacement,
+                                } => {
+                                    // Expand the replacement template using the capture
+                                    // groups of the regex matched against the source labels.
+                                    let mut value = String::new();
+                                    if let Some(captures) = selector.regex.captures(&label_values) {
+                                        captures.expand(replacement, &mut value);
+                                    }
+                                    set_label_value(&mut sample, target_label, value);
+                                }
+                                config::LabelFilterAction::LabelDrop { regex } => {
+                                    sample.labels.retain(|name, _| !regex.is_match(name));
+                                }
+                                config::LabelFilterAction::LabelKeep { regex } => {
+                                    sample.labels.retain(|name, _| regex.is_match(name));
+                                }
+                                config::LabelFilterAction::RenameMetric { regex, replacement } => {
+                                    if let Some(captures) = regex.captures(&sample.metric) {
+                                        let mut new_name = String::new();
+                                        captures.expand(replacement, &mut new_name);
+                                        if is_valid_metric_name(&new_name) {
+                                            sample.metric = new_name;
+                                        }
+                                    }
+                                }
+                                config::LabelFilterAction::HashMod {
+                                    modulus,
+                                    target_label,
+                                    query_parameter,
+                                } => {
+                                    // Same hash as Prometheus: the last 8 bytes of the
+                                    // MD5 sum, as a big endian integer.
+                                    let digest = md5::compute(label_values.as_bytes());
+                                    let hash =
+                                        u64::from_be_bytes(digest.0[8..].try_into().unwrap())
+                                            % modulus;
+                                    sample
+                                        .labels
+                                        .insert(target_label.to_string(), hash.to_string());
+                                    if let Some(shard) =
+                                        query_parameter.as_ref().and_then(|p| shards.get(p))
+                                    {
+                                        if *shard != hash {
+                                            other_shard = true;
+                                        }
+                                    }
+                                }
+                                config::LabelFilterAction::Scale { factor } => {
+                                    transform_value(&mut sample, |v| v * factor);
+                                }
+                                config::LabelFilterAction::Offset { amount } => {
+                                    transform_value(&mut sample, |v| v + amount);
+                                }
+                                // Comparisons are written so that NaN values stay NaN.
+                                config::LabelFilterAction::ClampMin { min } => {
+                                    transform_value(
+                                        &mut sample,
+                                        |v| if v < *min { *min } else { v },
+                                    );
+                                }
+                                config::LabelFilterAction::ClampMax { max } => {
+                                    transform_value(
+                                        &mut sample,
+                                        |v| if v > *max { *max } else { v },
+                                    );
+                                }
+                                config::LabelFilterAction::Round { to_nearest } => {
+                                    let inverse = 1.0 / to_nearest;
+                                    transform_value(&mut sample, |v| {
+                                        (v * inverse + 0.5).floor() / inverse
+                                    });
+                                }
+                                config::LabelFilterAction::Aggregate { .. } => {
+                                    aggregate = Some(((selector_index, action_index), action));
+                                }
+                                config::LabelFilterAction::SetLabel {
+                                    name,
+                                    value,
+                                    on_conflict,
+                                } => {
+                                    add_label(&mut sample, name, value, *on_conflict);
+                                }
+                                config::LabelFilterAction::StripExemplars => {
+                                    strip_exemplars(&mut sample);
+                                }
+                                config::LabelFilterAction::LabelMap { regex, replacement } => {
+                                    let renames = sample
+                                        .labels
+                                        .keys()
+                                        .filter_map(|name| {
+                                            regex.captures(name).map(|captures| {
+                                                let mut new_name = String::new();
+                                                captures.expand(replacement, &mut new_name);
+                                                (name.clone(), new_name)
+                                            })
+                                        })
+                                        .collect::<Vec<(String, String)>>();
+                                    for (name, new_name) in renames {
+                                        if new_name == name
+                                            || !config::is_valid_label_name(&new_name)
+                                        {
+                                            continue;
+                                        }
+                                        if let Some(value) = sample.labels.remove(&name) {
+                                            sample.labels.insert(new_name, value);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Ignore this sample if the conclusion is that we were going to drop it anyway.
+                if let Some(trulykeep) = keep {
+                    if !trulykeep {
+                        continue;
+                    }
+                }
+                if other_shard {
+                    continue;
+                }
+
+                for cached_sample in uncached {
+                    cache.put(cached_sample, now);
+                }
+
+                // Temporary labels are only meant to be used while filtering.
+                sample.labels.retain(|name, _| !name.starts_with("__tmp"));
+
+                if self.target.timestamps == config::TimestampPolicy::Strip {
+                    sample.timestamp = None;
+                }
+
+                // Aggregates of counters other than sums are served as gauges,
+                // so they lose the suffix reserved to counters.
+                if let Some((_, config::LabelFilterAction::Aggregate { operation, .. })) = aggregate
+                {
+                    if matches!(sample.value, Value::Counter(_))
+                        && *operation != config::AggregationOperation::Sum
+                    {
+                        if let Some(gauge_name) = sample.metric.strip_suffix("_total") {
+                            sample.metric = gauge_name.to_string();
+                        }
+                    }
+                }
+
+                // Add this sample's metric name documentation if not yet added,
+                // under the name the metric will be served with.
+                if !docs.contains_key(&sample.metric) && series.docs.contains_key(&original_metric)
+                {
+                    docs.insert(
+                        sample.metric.clone(),
+                        series.docs.get(&original_metric).unwrap().clone(),
+                    );
+                }
+                if !units.contains_key(&sample.metric) {
+                    if let Some(unit) = series.units.get(&original_metric) {
+                        units.insert(sample.metric.clone(), unit.clone());
+                    }
+                }
+
+                let mut aggregated = None;
+                if let Some((
+                    position,
+                    config::LabelFilterAction::Aggregate {
+                        operation,
+                        by,
+                        without,
+                    },
+                )) = aggregate
+                {
+                    // Histograms and summaries are not aggregated.
+                    if let Some(val) = sample.value.scalar() {
+                        if let Some(by) = by {
+                            sample.labels.retain(|name, _| by.contains(name));
+                        } else if let Some(without) = without {
+                            sample.labels.retain(|name, _| !without.contains(name));
+                        } else {
+                            sample.labels.clear();
+                        }
+                        // Aggregates have no creation time, timestamp
+                        // nor exemplar of their own.
+                        sample.created = None;
+                        sample.timestamp = None;
+                        sample.exemplar = None;
+                        let key = (position, sample.metric.clone(), sample.labels.clone());
+                        if let Some((_, aggregation)) = groups.get_mut(&key) {
+                            // Only the first sample of a group is served,
+                            // so the others are not subject to limits.
+                            aggregation.add(val);
+                            continue;
+                        }
+                        aggregated = Some((key, Aggregation::new(*operation), val));
+                    }
+                }
+
+                if let Some(errmsg) = self.limit_violation(&sample, *series_count) {
+                    if !truncate {
+                        return Err(errmsg);
+                    }
+                    dropped += sample.series_count();
+                    continue;
+                }
+                *series_count += sample.series_count();
+                samples.push(sample);
+                if let Some((key, mut aggregation, val)) = aggregated {
+                    aggregation.add(val);
+                    groups.insert(key, (samples.len() - 1, aggregation));
+                }
+            }
+        }
+
+        if dropped > 0 {
+            self.count_dropped(dropped);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        merge_families, safely_clone_request_headers, safely_clone_response_headers,
+        strip_unparseable_formats, ExpositionFormat, FilterState,
+    };
+    use crate::config::{
+        ConnectTo, HttpProxyTarget, LabelConflictPolicy, LabelFilter, LimitExceededAction,
+        MergeConflictPolicy, MergePolicy, QueryParameters, RequestHeaders, ResponseHeaders,
+        SampleLimits, SecretFile, TimestampPolicy,
+    };
+    use crate::scrape::{Labels, Parser, Sample, Scrape, TextFormat, Value};
+    use axum::http::{HeaderMap, StatusCode};
+    use duration_string::DurationString;
+    use hyper::body::Bytes;
+    use pretty_assertions::assert_eq as pretty_assert_eq;
+    use prometheus::Encoder;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn make_test_proxy_target(filters: Vec<LabelFilter>) -> HttpProxyTarget {
+        HttpProxyTarget {
+            connect_to: vec![ConnectTo {
+                url: url::Url::from_str("http://localhost:8080/metrics").unwrap(),
+                timeout: DurationString::new(Duration::new(5, 0)),
+                bearer_token_file: None,
+                basic_auth: None,
+                headers: reqwest::header::HeaderMap::new(),
+                tls: None,
+                tls_client_config: None,
+                labels: HashMap::new(),
+            }],
+            merge: MergePolicy::default(),
+            label_filters: filters,
+            cache_duration: DurationString::new(Duration::new(0, 0)),
+            extra_labels: HashMap::new(),
+            extra_labels_on_conflict: LabelConflictPolicy::default(),
+            limits: SampleLimits::default(),
+            timestamps: TimestampPolicy::default(),
+            preserve_order: true,
+            request_headers: RequestHeaders::default(),
+            response_headers: ResponseHeaders::default(),
+            query_parameters: QueryParameters::default(),
+        }
+    }
+
+    fn make_adapter_filter_tester(filters: Vec<LabelFilter>) -> crate::proxy::MetricsProxier {
+        crate::proxy::MetricsProxier::from(make_test_proxy_target(filters))
+    }
+
+    struct TestPayload {
+        sorted_text: String,
+        parsed_scrape: Scrape,
+    }
+
+    impl TestPayload {
+        fn from_scrape(scrape: Scrape) -> Self {
+            let chunk = ExpositionFormat::Prometheus.render(&scrape);
+            let rendered = std::str::from_utf8(chunk.as_ref()).unwrap();
+            let mut sorted_rendered: Vec<String> = rendered.lines().map(|s| s.to_owned()).collect();
+            sorted_rendered.sort();
+            let sorted_text = sorted_rendered.join("\n");
+            TestPayload {
+                sorted_text,
+                parsed_scrape: scrape,
+            }
+        }
+
+        fn from_text(text: &str) -> Self {
+            let parsed_scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+            TestPayload::from_scrape(parsed_scrape)
+        }
+    }
+
+    #[test]
+    fn test_proxy_no_filtering() {
+        let adapter = make_adapter_filter_tester(vec![]);
+        let text = r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+node_softnet_times_squeezed_total{cpu="1"} 0
+node_softnet_times_squeezed_total{cpu="10"} 0
+node_softnet_times_squeezed_total{cpu="11"} 0
+node_softnet_times_squeezed_total{cpu="12"} 0
+node_softnet_times_squeezed_total{cpu="13"} 0
+node_softnet_times_squeezed_total{cpu="14"} 0
+node_softnet_times_squeezed_total{cpu="15"} 0
+node_softnet_times_squeezed_total{cpu="2"} 0
+node_softnet_times_squeezed_total{cpu="3"} 0
+node_softnet_times_squeezed_total{cpu="4"} 0
+node_softnet_times_squeezed_total{cpu="5"} 0
+node_softnet_times_squeezed_total{cpu="6"} 0
+node_softnet_times_squeezed_total{cpu="7"} 0
+node_softnet_times_squeezed_total{cpu="8"} 0
+node_softnet_times_squeezed_total{cpu="9"} 0
+"#;
+        let inp_ = TestPayload::from_text(text);
+        let exp_ = TestPayload::from_text(text);
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_one_label_filtering() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_softnet_times_squeezed_total
+  actions: [drop]
+- source_labels: [cpu]
+  regex: "1"
+  actions: [keep]
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+node_softnet_times_squeezed_total{cpu="1"} 0
+node_softnet_times_squeezed_total{cpu="10"} 0
+node_softnet_times_squeezed_total{cpu="11"} 0
+node_softnet_times_squeezed_total{cpu="12"} 0
+node_softnet_times_squeezed_total{cpu="13"} 0
+node_softnet_times_squeezed_total{cpu="14"} 0
+node_softnet_times_squeezed_total{cpu="15"} 0
+node_softnet_times_squeezed_total{cpu="2"} 0
+node_softnet_times_squeezed_total{cpu="3"} 0
+node_softnet_times_squeezed_total{cpu="4"} 0
+node_softnet_times_squeezed_total{cpu="5"} 0
+node_softnet_times_squeezed_total{cpu="6"} 0
+node_softnet_times_squeezed_total{cpu="7"} 0
+node_softnet_times_squeezed_total{cpu="8"} 0
+node_softnet_times_squeezed_total{cpu="9"} 0
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="1"} 0
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_replace() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- source_labels: [instance]
+  regex: "([^.]+)[.]internal[.]example[.]com:(?P<port>[0-9]+)"
+  actions:
+  - replace:
+      target_label: instance
+      replacement: "$1:${port}"
+- source_labels: [__name__, cpu]
+  regex: "node_softnet_times_squeezed_total;(.+)"
+  actions:
+  - replace:
+      target_label: core
+  - replace:
+      target_label: cpu
+      replacement: ""
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0",instance="host1.internal.example.com:9100"} 0
+node_softnet_times_squeezed_total{cpu="1",instance="host2.example.com:9100"} 0
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{core="0",instance="host1:9100"} 0
+node_softnet_times_squeezed_total{core="1",instance="host2.example.com:9100"} 0
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_replace_metric_name() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- source_labels: [__name__, device]
+  regex: "node_disk_(.+)_total;(.*)"
+  actions:
+  - replace:
+      target_label: __name__
+      replacement: "disk_${1}_total"
+- source_labels: [device]
+  regex: "(.*)"
+  actions:
+  - replace:
+      target_label: __name__
+      replacement: "$1"
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_disk_reads_completed_total The total number of reads completed successfully.
+# TYPE node_disk_reads_completed_total counter
+node_disk_reads_completed_total{device="sda"} 100
+node_disk_reads_completed_total{device="0"} 1
+node_disk_reads_completed_total 7
+"#,
+        );
+        // Neither empty names nor names starting with a digit are valid,
+        // so the previous name is kept.
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP sda The total number of reads completed successfully.
+# TYPE sda counter
+sda{device="sda"} 100
+# HELP disk_reads_completed_total The total number of reads completed successfully.
+# TYPE disk_reads_completed_total counter
+disk_reads_completed_total{device="0"} 1
+disk_reads_completed_total 7
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_labeldrop_labelkeep() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: http_requests_total
+  actions:
+  - labeldrop:
+      regex: request_id|pod_.*
+- regex: node_softnet_times_squeezed_total
+  actions:
+  - labelkeep:
+      regex: cpu
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200",request_id="a",pod_uid="x"} 1
+http_requests_total{code="200",request_id="b",pod_uid="y"} 2
+http_requests_total{code="500",request_id="c",pod_uid="z"} 3
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0",instance="host1"} 0
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200"} 1
+http_requests_total{code="500"} 3
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_labelmap() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_disk_io_time_seconds_total
+  actions:
+  - labelmap:
+      regex: __meta_foo_(.+)
+  - labelmap:
+      regex: device
+      replacement: disk_device
+- regex: foo
+  actions:
+  - labelmap:
+      regex: x_(.+)
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_disk_io_time_seconds_total Total seconds spent doing I/Os.
+# TYPE node_disk_io_time_seconds_total counter
+node_disk_io_time_seconds_total{__meta_foo_rack="r1",device="sda"} 1
+# TYPE foo gauge
+foo{x_1a="v"} 1
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_disk_io_time_seconds_total Total seconds spent doing I/Os.
+# TYPE node_disk_io_time_seconds_total counter
+node_disk_io_time_seconds_total{disk_device="sda",rack="r1"} 1
+# TYPE foo gauge
+foo{x_1a="v"} 1
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_extra_labels() {
+        let text = r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+node_load1{datacenter="backend-dc",exported_datacenter="older-dc"} 0.7
+"#;
+        for (policy, expected) in [
+            (
+                LabelConflictPolicy::Overwrite,
+                r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1{datacenter="dc1",node_role="replica"} 0.5
+node_load1{datacenter="dc1",exported_datacenter="older-dc",node_role="replica"} 0.7
+"#,
+            ),
+            (
+                LabelConflictPolicy::Keep,
+                r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1{datacenter="dc1",node_role="replica"} 0.5
+node_load1{datacenter="backend-dc",exported_datacenter="older-dc",node_role="replica"} 0.7
+"#,
+            ),
+            (
+                LabelConflictPolicy::Rename,
+                r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1{datacenter="dc1",node_role="replica"} 0.5
+node_load1{datacenter="dc1",exported_datacenter="older-dc",exported_exported_datacenter="backend-dc",node_role="replica"} 0.7
+"#,
+            ),
+        ] {
+            let mut target = make_test_proxy_target(
+                serde_yaml::from_str(
+                    r#"
+- regex: node_load1
+  actions:
+  - set_label:
+      name: node_role
+      value: replica
+"#,
+                )
+                .unwrap(),
+            );
+            target.extra_labels = HashMap::from([("datacenter".to_string(), "dc1".to_string())]);
+            target.extra_labels_on_conflict = policy;
+            let adapter = crate::proxy::MetricsProxier::from(target);
+            let inp_ = TestPayload::from_text(text);
+            let exp_ = TestPayload::from_text(expected);
+            let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+            let out_ = TestPayload::from_scrape(filtered);
+            pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+        }
+    }
+
+    #[test]
+    fn test_proxy_rename_metric() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_.*
+  actions:
+  - rename_metric:
+      regex: node_(.+)_squeezed_total
+      replacement: host_${1}_squeezes_total
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP host_softnet_times_squeezes_total Number of times processing packets ran out of quota
+# TYPE host_softnet_times_squeezes_total counter
+host_softnet_times_squeezes_total{cpu="0"} 0
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_undocumented_types() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_softnet_times_squeezed_total
+  actions:
+  - rename_metric:
+      regex: node_(.+)
+      replacement: host_${1}
+- regex: node_cpu_seconds_total
+  actions:
+  - aggregate:
+      operation: sum
+      by: [mode]
+"#,
+            )
+            .unwrap(),
+        );
+        let text = r#"
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 1
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{cpu="0",mode="idle"} 10
+node_cpu_seconds_total{cpu="1",mode="idle"} 20
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="+Inf"} 3
+http_request_duration_seconds_sum 2.75
+http_request_duration_seconds_count 3
+node_boot_time_seconds 1.7e9
+"#;
+        let filtered =
+            adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+        let rendered = ExpositionFormat::Prometheus.render(&filtered);
+        pretty_assert_eq!(
+            r#"# TYPE host_softnet_times_squeezed_total counter
+host_softnet_times_squeezed_total{cpu="0"} 1e0
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{mode="idle"} 3e1
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="+Inf"} 3e0
+http_request_duration_seconds_sum 2.75e0
+http_request_duration_seconds_count 3e0
+node_boot_time_seconds 1.7e9
+"#,
+            std::str::from_utf8(rendered.as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_proxy_hashmod_shards() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- source_labels: [cpu]
+  regex: .*
+  actions:
+  - hashmod:
+      modulus: 3
+      target_label: __tmp_shard
+      query_parameter: shard
+- source_labels: [__tmp_shard]
+  regex: "(.+)"
+  actions:
+  - replace:
+      target_label: shard
+- source_labels: [cpu]
+  regex: "1"
+  actions: [keep]
+"#,
+            )
+            .unwrap(),
+        );
+        let text = (0..16)
+            .map(|cpu| format!("node_softnet_times_squeezed_total{{cpu=\"{cpu}\"}} 0"))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        // Without a shard requested, all samples are served, and the
+        // temporary label is stripped from them.
+        let unsharded =
+            adapter.apply_filters(TestPayload::from_text(&text).parsed_scrape, &HashMap::new());
+        assert_eq!(unsharded.samples.len(), 16);
+        assert!(unsharded
+            .samples
+            .iter()
+            .all(|s| !s.labels.contains_key("__tmp_shard")));
+
+        // Each shard only contains its own samples, and together
+        // they contain all samples, even those kept by a later filter.
+        let mut total = 0;
+        for shard in 0..3 {
+            let query = HashMap::from([("shard".to_string(), shard.to_string())]);
+            let shards = adapter.requested_shards(&query).unwrap();
+            let sharded =
+                adapter.apply_filters(TestPayload::from_text(&text).parsed_scrape, &shards);
+            assert!(sharded
+                .samples
+                .iter()
+                .all(|s| s.labels.get("shard") == Some(&shard.to_string())));
+            total += sharded.samples.len();
+        }
+        assert_eq!(total, 16);
+
+        let query = HashMap::from([("shard".to_string(), "3".to_string())]);
+        assert!(adapter.requested_shards(&query).is_err());
+    }
+
+    #[test]
+    fn test_proxy_value_transformations() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_memory_.*_bytes
+  actions:
+  - scale:
+      factor: 0.000001
+  - round:
+      to_nearest: 0.5
+- regex: node_hwmon_temp_celsius
+  actions:
+  - offset:
+      amount: 273.15
+  - clamp_min:
+      min: 0
+  - clamp_max:
+      max: 400
+- regex: http_request_duration_seconds
+  actions:
+  - scale:
+      factor: 1000
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_memory_MemFree_bytes Memory information field MemFree_bytes.
+# TYPE node_memory_MemFree_bytes gauge
+node_memory_MemFree_bytes 1.2345678e+09
+# HELP node_hwmon_temp_celsius Hardware monitor for temperature (input)
+# TYPE node_hwmon_temp_celsius gauge
+node_hwmon_temp_celsius{sensor="temp1"} -300
+node_hwmon_temp_celsius{sensor="temp2"} 200
+node_hwmon_temp_celsius{sensor="temp3"} NaN
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_memory_MemFree_bytes Memory information field MemFree_bytes.
+# TYPE node_memory_MemFree_bytes gauge
+node_memory_MemFree_bytes 1234.5
+# HELP node_hwmon_temp_celsius Hardware monitor for temperature (input)
+# TYPE node_hwmon_temp_celsius gauge
+node_hwmon_temp_celsius{sensor="temp1"} 0
+node_hwmon_temp_celsius{sensor="temp2"} 400
+node_hwmon_temp_celsius{sensor="temp3"} NaN
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_aggregate() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_cpu_seconds_total
+  actions:
+  - aggregate:
+      operation: sum
+      by: [mode]
+- regex: node_cpu_scaling_frequency_hertz
+  actions:
+  - aggregate:
+      operation: avg
+      without: [cpu]
+- regex: node_softnet_times_squeezed_total
+  actions:
+  - aggregate:
+      operation: count
+- source_labels: [mode]
+  regex: steal
+  actions:
+  - drop
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_cpu_seconds_total Seconds the CPUs spent in each mode.
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{cpu="0",mode="idle"} 10
+node_cpu_seconds_total{cpu="0",mode="user"} 1
+node_cpu_seconds_total{cpu="0",mode="steal"} 1
+node_cpu_seconds_total{cpu="1",mode="idle"} 20
+node_cpu_seconds_total{cpu="1",mode="user"} 2
+node_cpu_seconds_total{cpu="1",mode="steal"} 2
+# HELP node_cpu_scaling_frequency_hertz Current scaled CPU thread frequency in hertz.
+# TYPE node_cpu_scaling_frequency_hertz gauge
+node_cpu_scaling_frequency_hertz{cpu="0",governor="performance"} 1e+09
+node_cpu_scaling_frequency_hertz{cpu="1",governor="performance"} 2e+09
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+node_softnet_times_squeezed_total{cpu="1"} 0
+node_softnet_times_squeezed_total{cpu="2"} 0
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_cpu_seconds_total Seconds the CPUs spent in each mode.
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{mode="idle"} 30
+node_cpu_seconds_total{mode="user"} 3
+# HELP node_cpu_scaling_frequency_hertz Current scaled CPU thread frequency in hertz.
+# TYPE node_cpu_scaling_frequency_hertz gauge
+node_cpu_scaling_frequency_hertz{governor="performance"} 1.5e+09
+# HELP node_softnet_times_squeezed Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed gauge
+node_softnet_times_squeezed 3
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_limits() {
+        let text = r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+node_softnet_times_squeezed_total{cpu="1"} 0
+node_softnet_times_squeezed_total{cpu="2",request_id="0123456789abcdef0123456789abcdef0123456789abcdef"} 0
+node_softnet_times_squeezed_total{cpu="3"} 0
+"#;
+        let mut target = make_test_proxy_target(vec![]);
+        target.limits = SampleLimits {
+            sample_limit: Some(2),
+            label_value_length_limit: Some(40),
+            ..Default::default()
+        };
+        let adapter = crate::proxy::MetricsProxier::from(target.clone());
+        assert!(adapter
+            .filter_family(
+                &mut FilterState::new(),
+                TestPayload::from_text(text).parsed_scrape,
+                &HashMap::new()
+            )
+            .is_err());
+
+        target.limits.on_limit_exceeded = LimitExceededAction::Truncate;
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
+# TYPE node_softnet_times_squeezed_total counter
+node_softnet_times_squeezed_total{cpu="0"} 0
+node_softnet_times_squeezed_total{cpu="1"} 0
+"#,
+        );
+        let limited =
+            adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(limited);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+
+        // Limits apply to the metrics as served, so aggregating metrics
+        // keeps them within the limits.
+        let mut target = make_test_proxy_target(
+            serde_yaml::from_str(
+                r#"
+- regex: node_softnet_times_squeezed_total
+  actions:
+  - labeldrop:
+      regex: request_id
+  - aggregate:
+      operation: sum
+"#,
+            )
+            .unwrap(),
+        );
+        target.limits = SampleLimits {
+            sample_limit: Some(1),
+            label_value_length_limit: Some(40),
+            ..Default::default()
+        };
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let aggregated =
+            adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+        assert_eq!(aggregated.samples.len(), 1);
+    }
+
+    #[test]
+    fn test_proxy_value_predicates() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_disk_.*
+  value_matches:
+    op: eq
+    value: 0
+  actions: [drop]
+- regex: node_disk_.*
+  is_nan: true
+  actions: [drop]
+- regex: node_hwmon_temp_celsius
+  value_matches:
+    op: gt
+    value: 100
+  actions:
+  - clamp_max:
+      max: 100
+  - set_label:
+      name: clamped
+      value: "true"
+"#,
+            )
+            .unwrap(),
+        );
+        let inp_ = TestPayload::from_text(
+            r#"
+# HELP node_disk_reads_completed_total The total number of reads completed successfully.
+# TYPE node_disk_reads_completed_total counter
+node_disk_reads_completed_total{device="sda"} 100
+node_disk_reads_completed_total{device="sdb"} 0
+node_disk_reads_completed_total{device="sdc"} NaN
+# HELP node_hwmon_temp_celsius Hardware monitor for temperature (input)
+# TYPE node_hwmon_temp_celsius gauge
+node_hwmon_temp_celsius{sensor="temp1"} 50
+node_hwmon_temp_celsius{sensor="temp2"} 150
+"#,
+        );
+        let exp_ = TestPayload::from_text(
+            r#"
+# HELP node_disk_reads_completed_total The total number of reads completed successfully.
+# TYPE node_disk_reads_completed_total counter
+node_disk_reads_completed_total{device="sda"} 100
+# HELP node_hwmon_temp_celsius Hardware monitor for temperature (input)
+# TYPE node_hwmon_temp_celsius gauge
+node_hwmon_temp_celsius{sensor="temp1"} 50
+node_hwmon_temp_celsius{clamped="true",sensor="temp2"} 100
+"#,
+        );
+        let filtered = adapter.apply_filters(inp_.parsed_scrape, &HashMap::new());
+        let out_ = TestPayload::from_scrape(filtered);
+        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+    }
+
+    #[test]
+    fn test_proxy_openmetrics() {
+        let text = r#"# TYPE http_requests counter
+# HELP http_requests The total number of HTTP requests.
+http_requests_total{code="200"} 1027
+http_requests_created{code="200"} 1.6e+09
+# TYPE http_request_duration_seconds histogram
+# UNIT http_request_duration_seconds seconds
+# HELP http_request_duration_seconds A histogram of the request duration.
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+http_request_duration_seconds_created 1.6e+09
+# TYPE build_info unknown
+build_info{version="1.0"} 1
+# EOF
+"#;
+        let scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+
+        let openmetrics = ExpositionFormat::OpenMetrics.render(&scrape);
+        pretty_assert_eq!(
+            r#"# TYPE http_requests counter
+# HELP http_requests The total number of HTTP requests.
+http_requests_total{code="200"} 1.027e3
+http_requests_created{code="200"} 1.6e9
+# TYPE http_request_duration_seconds histogram
+# UNIT http_request_duration_seconds seconds
+# HELP http_request_duration_seconds A histogram of the request duration.
+http_request_duration_seconds_bucket{le="0.05"} 2.4054e4
+http_request_duration_seconds_bucket{le="+Inf"} 1.4432e5
+http_request_duration_seconds_sum 5.3423e4
+http_request_duration_seconds_count 1.4432e5
+http_request_duration_seconds_created 1.6e9
+# TYPE build_info unknown
+build_info{version="1.0"} 1e0
+# EOF
+"#,
+            std::str::from_utf8(openmetrics.as_ref()).unwrap()
+        );
+
+        let prometheus = ExpositionFormat::Prometheus.render(&scrape);
+        pretty_assert_eq!(
+            r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200"} 1.027e3
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 2.4054e4
+http_request_duration_seconds_bucket{le="+Inf"} 1.4432e5
+http_request_duration_seconds_sum 5.3423e4
+http_request_duration_seconds_count 1.4432e5
+build_info{version="1.0"} 1e0
+"#,
+            std::str::from_utf8(prometheus.as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_proxy_exemplars() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- source_labels: [code]
+  regex: "500"
+  actions: [drop]
+- regex: http_request_duration_seconds
+  actions: [strip_exemplars]
+"#,
+            )
+            .unwrap(),
+        );
+        let text = r#"# TYPE http_requests counter
+http_requests_total{code="200"} 1027 # {trace_id="4bf92f3577b34da6"} 1 1.7e+09
+http_requests_total{code="500"} 3 # {trace_id="00f067aa0ba902b7"} 1
+# TYPE http_request_size_bytes histogram
+http_request_size_bytes_bucket{le="100"} 12 # {trace_id="a3ce929d0e0e4736"} 64
+http_request_size_bytes_bucket{le="+Inf"} 41
+http_request_size_bytes_sum 21504
+http_request_size_bytes_count 41
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="+Inf"} 3 # {trace_id="b7ad6b7169203331"} 0.25
+http_request_duration_seconds_sum 2.75
+http_request_duration_seconds_count 3
+# TYPE queue_length gauge
+queue_length 4 # {trace_id="e457b5a2e4d86bd1"} 1
+# EOF
+"#;
+        let scrape = Scrape::parse_format(
+            text.lines().map(|s| Ok(s.to_owned())),
+            TextFormat::OpenMetrics,
+        )
+        .unwrap();
+        let filtered = adapter.apply_filters(scrape, &HashMap::new());
+
+        let openmetrics = ExpositionFormat::OpenMetrics.render(&filtered);
+        pretty_assert_eq!(
+            r#"# TYPE http_requests counter
+http_requests_total{code="200"} 1.027e3 # {trace_id="4bf92f3577b34da6"} 1e0 1700000000
+# TYPE http_request_size_bytes histogram
+http_request_size_bytes_bucket{le="100"} 1.2e1 # {trace_id="a3ce929d0e0e4736"} 6.4e1
+http_request_size_bytes_bucket{le="+Inf"} 4.1e1
+http_request_size_bytes_sum 2.1504e4
+http_request_size_bytes_count 4.1e1
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="+Inf"} 3e0
+http_request_duration_seconds_sum 2.75e0
+http_request_duration_seconds_count 3e0
+# TYPE queue_length gauge
+queue_length 4e0
+# EOF
+"#,
+            std::str::from_utf8(openmetrics.as_ref()).unwrap()
+        );
+
+        // Only counters and buckets may have exemplars, whatever the sample.
+        let gauge = Scrape {
+            docs: HashMap::new(),
+            units: HashMap::new(),
+            samples: vec![Sample {
+                exemplar: filtered.samples[0].exemplar.clone(),
+                value: Value::Gauge(4.0),
+                ..filtered.samples[0].clone()
+            }],
+        };
+        assert!(
+            !std::str::from_utf8(ExpositionFormat::OpenMetrics.render(&gauge).as_ref())
+                .unwrap()
+                .contains("trace_id")
+        );
+
+        // The Prometheus text format has no exemplars.
+        let prometheus = ExpositionFormat::Prometheus.render(&filtered);
+        assert!(!std::str::from_utf8(prometheus.as_ref())
+            .unwrap()
+            .contains("trace_id"));
+    }
+
+    #[test]
+    fn test_format_negotiation() {
+        for (accept, expected) in [
+            (None, ExpositionFormat::Prometheus),
+            (Some("text/plain"), ExpositionFormat::Prometheus),
+            (Some("application/json"), ExpositionFormat::Json),
+            (
+                Some("application/json;q=0.5, */*"),
+                ExpositionFormat::Prometheus,
+            ),
+            (Some("image/png"), ExpositionFormat::Prometheus),
+            (
+                Some("application/openmetrics-text;version=1.0.0;q=0.5,application/openmetrics-text;version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"),
+                ExpositionFormat::OpenMetrics,
+            ),
+            (
+                Some("application/openmetrics-text;q=0.1, text/plain;q=0.9"),
+                ExpositionFormat::Prometheus,
+            ),
+            (
+                Some("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.1"),
+                ExpositionFormat::Protobuf,
+            ),
+            (
+                Some("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text"),
+                ExpositionFormat::Prometheus,
+            ),
+        ] {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert("accept", accept.parse().unwrap());
+            }
+            assert_eq!(ExpositionFormat::negotiate(&headers), expected);
+        }
+    }
+
+    #[test]
+    fn test_proxy_json() {
+        let text = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200",method="get"} 1027 1700000000123
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 0
+http_request_duration_seconds_bucket{le="+Inf"} 3
+http_request_duration_seconds_sum 2.75
+http_request_duration_seconds_count 3
+node_temperature NaN
+"#;
+        let scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        let rendered = ExpositionFormat::Json.render(&scrape);
+        let parsed: serde_json::Value = serde_json::from_slice(&rendered).unwrap();
+        pretty_assert_eq!(
+            serde_json::json!([
+                {
+                    "name": "http_requests_total",
+                    "type": "counter",
+                    "help": "The total number of HTTP requests.",
+                    "samples": [
+                        {
+                            "labels": {"code": "200", "method": "get"},
+                            "value": "1027",
+                            "timestamp": 1700000000123_i64,
+                        },
+                    ],
+                },
+                {
+                    "name": "http_request_duration_seconds",
+                    "type": "histogram",
+                    "help": null,
+                    "samples": [
+                        {
+                            "labels": {},
+                            "value": {
+                                "buckets": [
+                                    {"le": "0.05", "count": "0"},
+                                    {"le": "+Inf", "count": "3"},
+                                ],
+                                "sum": "2.75",
+                                "count": "3",
+                            },
+                            "timestamp": null,
+                        },
+                    ],
+                },
+                {
+                    "name": "node_temperature",
+                    "type": "untyped",
+                    "help": null,
+                    "samples": [
+                        {"labels": {}, "value": "NaN", "timestamp": null},
+                    ],
+                },
+            ]),
+            parsed
+        );
+
+        let empty = ExpositionFormat::Json.render(&Scrape::default());
+        assert_eq!("[]\n", std::str::from_utf8(&empty).unwrap());
+    }
+
+    #[test]
+    fn test_proxy_protobuf() {
+        use prometheus::{Encoder, ProtobufEncoder};
+
+        // The client library sorts families by name.
+        let text = r#"
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 0
+http_request_duration_seconds_bucket{le="1"} 2
+http_request_duration_seconds_sum 2.75
+http_request_duration_seconds_count 3
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="200",method="get"} 1027
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+"#;
+        let scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        let rendered = ExpositionFormat::Protobuf.render(&scrape);
+
+        // The same metrics, as encoded by the Prometheus client library.
+        let registry = prometheus::Registry::new();
+        let requests = prometheus::CounterVec::new(
+            prometheus::Opts::new("http_requests_total", "The total number of HTTP requests."),
+            &["method", "code"],
+        )
+        .unwrap();
+        requests.with_label_values(&["get", "200"]).inc_by(1027.0);
+        registry.register(Box::new(requests)).unwrap();
+        let duration = prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new(
+                "http_request_duration_seconds",
+                "A histogram of the request duration.",
+            )
+            .buckets(vec![0.05, 1.0]),
+        )
+        .unwrap();
+        for observation in [0.25, 0.5, 2.0] {
+            duration.observe(observation);
+        }
+        registry.register(Box::new(duration)).unwrap();
+        let load = prometheus::Gauge::new("node_load1", "1m load average.").unwrap();
+        load.set(0.5);
+        registry.register(Box::new(load)).unwrap();
+        let mut expected = vec![];
+        ProtobufEncoder::new()
+            .encode(&registry.gather(), &mut expected)
+            .unwrap();
+
+        assert_eq!(expected, rendered.to_vec());
+
+        // Families that cannot be encoded are left out rather than
+        // failing the whole response.
+        let scrape = Scrape {
+            docs: HashMap::new(),
+            units: HashMap::new(),
+            samples: vec![Sample {
+                metric: String::new(),
+                labels: Labels::new(),
+                value: Value::Gauge(1.0),
+                created: None,
+                timestamp: None,
+                exemplar: None,
+            }],
+        };
+        assert!(ExpositionFormat::Protobuf.render(&scrape).is_empty());
+    }
+
+    #[test]
+    fn test_strip_unparseable_formats() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "accept",
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"
+                .parse()
+                .unwrap(),
+        );
+        strip_unparseable_formats(&mut headers);
+        assert_eq!(headers["accept"], "text/plain;version=0.0.4;q=0.3");
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("accept", "application/vnd.google.protobuf".parse().unwrap());
+        strip_unparseable_formats(&mut headers);
+        assert!(!headers.contains_key("accept"));
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("accept", "application/json, */*;q=0.1".parse().unwrap());
+        strip_unparseable_formats(&mut headers);
+        assert_eq!(headers["accept"], "*/*;q=0.1");
+    }
+
+    /// Renders the metrics parsed from an exporter fixture in the given
+    /// format, and checks that parsing the result yields the same metrics,
+    /// and that the reference parser finds the same lines in the fixture
+    /// and in the result.
+    fn assert_round_trip(text: &str, format: ExpositionFormat) {
+        fn parse(text: &str) -> Scrape {
+            let mut scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+            scrape
+                .samples
+                .sort_by(|a, b| (&a.metric, &a.labels).cmp(&(&b.metric, &b.labels)));
+            scrape
+        }
+        fn reference(text: &str, format: ExpositionFormat) -> Vec<ReferenceSample> {
+            let (_, mut samples) = reference_parse(text, format);
+            for (_, labels, _) in samples.iter_mut() {
+                // Bucket bounds and quantiles may be spelled differently.
+                for (name, value) in labels.iter_mut() {
+                    if name == "le" || name == "quantile" {
+                        *value = value.parse::<f64>().unwrap().to_string();
+                    }
+                }
+                labels.sort();
+            }
+            samples.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+            samples
+        }
+
+        let parsed = parse(text);
+        let rendered = format.render(&parsed);
+        let rendered = std::str::from_utf8(rendered.as_ref()).unwrap();
+        let reparsed = parse(rendered);
+        pretty_assert_eq!(parsed.samples, reparsed.samples);
+        pretty_assert_eq!(parsed.docs, reparsed.docs);
+        pretty_assert_eq!(parsed.units, reparsed.units);
+        // The fixtures all come in the OpenMetrics flavor of escaping.
+        pretty_assert_eq!(
+            reference(text, ExpositionFormat::OpenMetrics),
+            reference(rendered, format)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_prometheus() {
+        assert_round_trip(
+            include_str!("../fixtures/rust_prometheus.prom"),
+            ExpositionFormat::Prometheus,
+        );
+        assert_round_trip(
+            include_str!("../fixtures/metrics_exporter.prom"),
+            ExpositionFormat::Prometheus,
+        );
+    }
+
+    #[test]
+    fn test_round_trip_openmetrics() {
+        let text = include_str!("../fixtures/openmetrics.txt");
+        let parsed = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        assert!(parsed
+            .samples
+            .iter()
+            .any(|sample| matches!(sample.value, Value::Summary(_)) && sample.created.is_some()));
+        assert_round_trip(
+            include_str!("../fixtures/openmetrics.txt"),
+            ExpositionFormat::OpenMetrics,
+        );
+        assert_round_trip(
+            include_str!("../fixtures/rust_prometheus.prom"),
+            ExpositionFormat::OpenMetrics,
+        );
+        assert_round_trip(
+            include_str!("../fixtures/metrics_exporter.prom"),
+            ExpositionFormat::OpenMetrics,
+        );
+    }
+
+    #[test]
+    fn test_round_trip_protobuf() {
+        /// Decodes length-delimited `MetricFamily` messages into samples,
+        /// with their documentation.
+        fn decode(encoded: &[u8]) -> Scrape {
+            use crate::scrape::{Histogram, HistogramCount, Summary, SummaryCount};
+            use prometheus::proto;
+            let mut input = protobuf::CodedInputStream::from_bytes(encoded);
+            let mut scrape = Scrape {
+                docs: HashMap::new(),
+                units: HashMap::new(),
+                samples: vec![],
+            };
+            while !input.eof().unwrap() {
+                let family: proto::MetricFamily = input.read_message().unwrap();
+                if family.has_help() {
+                    scrape
+                        .docs
+                        .insert(family.get_name().to_string(), family.get_help().to_string());
+                }
+                for metric in family.get_metric() {
+                    let value = match family.get_field_type() {
+                        proto::MetricType::COUNTER => {
+                            Value::Counter(metric.get_counter().get_value())
+                        }
+                        proto::MetricType::GAUGE => Value::Gauge(metric.get_gauge().get_value()),
+                        proto::MetricType::UNTYPED => {
+                            Value::Untyped(metric.get_untyped().get_value())
+                        }
+                        proto::MetricType::HISTOGRAM => {
+                            let h = metric.get_histogram();
+                            Value::Histogram(Histogram {
+                                buckets: h
+                                    .get_bucket()
+                                    .iter()
+                                    .map(|b| HistogramCount {
+                                        less_than: b.get_upper_bound(),
+                                        count: b.get_cumulative_count() as f64,
+                                        exemplar: None,
+                                    })
+                                    .collect(),
+                                sum: Some(h.get_sample_sum()),
+                                count: Some(h.get_sample_count() as f64),
+                            })
+                        }
+                        proto::MetricType::SUMMARY => {
+                            let s = metric.get_summary();
+                            Value::Summary(Summary {
+                                quantiles: s
+                                    .get_quantile()
+                                    .iter()
+                                    .map(|q| SummaryCount {
+                                        quantile: q.get_quantile(),
+                                        count: q.get_value(),
+                                    })
+                                    .collect(),
+                                sum: Some(s.get_sample_sum()),
+                                count: Some(s.get_sample_count() as f64),
+                            })
+                        }
+                    };
+                    scrape.samples.push(Sample {
+                        metric: family.get_name().to_string(),
+                        labels: metric
+                            .get_label()
+                            .iter()
+                            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                            .collect(),
+                        value,
+                        created: None,
+                        timestamp: None,
+                        exemplar: None,
+                    });
+                }
+            }
+            scrape
+        }
+
+        for text in [
+            include_str!("../fixtures/metrics_exporter.prom"),
+            include_str!("../fixtures/openmetrics.txt"),
+        ] {
+            let mut parsed = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+            let decoded = decode(&ExpositionFormat::Protobuf.render(&parsed));
+            // The protocol buffer format carries neither creation
+            // times nor exemplars of the text formats.
+            let summaries = |samples: &[Sample]| {
+                samples
+                    .iter()
+                    .filter(|sample| matches!(sample.value, Value::Summary(_)))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            for sample in &mut parsed.samples {
+                sample.created = None;
+            }
+            assert!(!summaries(&parsed.samples).is_empty());
+            pretty_assert_eq!(summaries(&parsed.samples), summaries(&decoded.samples));
+            for (metric, help) in &decoded.docs {
+                assert_eq!(Some(help), parsed.docs.get(metric));
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_like_prometheus_encoder() {
+        let registry = prometheus::Registry::new();
+        let requests = prometheus::IntCounterVec::new(
+            prometheus::Opts::new("http_requests_total", "Number of HTTP requests made."),
+            &["handler", "code"],
+        )
+        .unwrap();
+        requests
+            .with_label_values(&["/metrics", "200"])
+            .inc_by(1027);
+        requests.with_label_values(&["/api", "500"]).inc_by(3);
+        registry.register(Box::new(requests)).unwrap();
+        let temperature = prometheus::Gauge::new(
+            "temperature_celsius",
+            "Temperature in \"degrees\" Celsius.\nMeasured with a \\ sensor.",
+        )
+        .unwrap();
+        temperature.set(-12.5);
+        registry.register(Box::new(temperature)).unwrap();
+        let durations = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("request_duration_seconds", "Request latencies.")
+                .buckets(vec![0.01, 0.1, 1.0]),
+            &["handler"],
+        )
+        .unwrap();
+        for duration in [0.003, 0.02, 0.4, 1.7] {
+            durations.with_label_values(&["/metrics"]).observe(duration);
+        }
+        registry.register(Box::new(durations)).unwrap();
+
+        let mut encoded = vec![];
+        prometheus::TextEncoder::new()
+            .encode(&registry.gather(), &mut encoded)
+            .unwrap();
+        let encoded = String::from_utf8(encoded).unwrap();
+        let scrape = Scrape::parse(encoded.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        let rendered = ExpositionFormat::Prometheus.render(&scrape);
+        let rendered = std::str::from_utf8(rendered.as_ref()).unwrap();
+        // Values are spelled differently, so only the comments are compared
+        // verbatim, and the samples through the reference parser.
+        let comments = |text: &str| {
+            let mut lines: Vec<_> = text.lines().filter(|l| l.starts_with('#')).collect();
+            lines.sort();
+            lines.join("\n")
+        };
+        pretty_assert_eq!(comments(&encoded), comments(rendered));
+        let samples = |text: &str| {
+            let (_, mut samples) = reference_parse(text, ExpositionFormat::Prometheus);
+            samples.sort();
+            samples
+        };
+        pretty_assert_eq!(samples(&encoded), samples(rendered));
+    }
+
+    #[test]
+    fn test_proxy_timestamps() {
+        let text = r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5 1700000000123
+node_load5 0.25
+"#;
+        let scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        let rendered = ExpositionFormat::OpenMetrics.render(&scrape);
+        pretty_assert_eq!(
+            r#"# TYPE node_load1 gauge
+# HELP node_load1 1m load average.
+node_load1 5e-1 1700000000.123
+# TYPE node_load5 unknown
+node_load5 2.5e-1
+# EOF
+"#,
+            std::str::from_utf8(rendered.as_ref()).unwrap()
+        );
+        let reparsed = Scrape::parse_format(
+            std::str::from_utf8(rendered.as_ref())
+                .unwrap()
+                .lines()
+                .map(|s| Ok(s.to_owned())),
+            TextFormat::OpenMetrics,
+        )
+        .unwrap();
+        assert_eq!(reparsed.samples[0].timestamp, Some(1700000000123));
+
+        for (policy, expected) in [
+            (
+                TimestampPolicy::Keep,
+                r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5 1700000000123
+node_load5 0.25
+"#,
+            ),
+            (
+                TimestampPolicy::Strip,
+                r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+node_load5 0.25
+"#,
+            ),
+        ] {
+            let mut target = make_test_proxy_target(vec![]);
+            target.timestamps = policy;
+            let adapter = crate::proxy::MetricsProxier::from(target);
+            let exp_ = TestPayload::from_text(expected);
+            let filtered =
+                adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+            let out_ = TestPayload::from_scrape(filtered);
+            pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+        }
+
+        // Samples served from the cache carry the time they were fetched.
+        let mut target = make_test_proxy_target(
+            serde_yaml::from_str(
+                r#"
+- regex: node_load.*
+  actions:
+  - reduce_time_resolution:
+      resolution: 1h
+"#,
             )
             .unwrap(),
         );
-        let inp_ = TestPayload::from_text(
-            r#"
-# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
-# TYPE node_softnet_times_squeezed_total counter
-node_softnet_times_squeezed_total{cpu="0"} 0
-node_softnet_times_squeezed_total{cpu="1"} 0
-node_softnet_times_squeezed_total{cpu="10"} 0
-node_softnet_times_squeezed_total{cpu="11"} 0
-node_softnet_times_squeezed_total{cpu="12"} 0
-node_softnet_times_squeezed_total{cpu="13"} 0
-node_softnet_times_squeezed_total{cpu="14"} 0
-node_softnet_times_squeezed_total{cpu="15"} 0
-node_softnet_times_squeezed_total{cpu="2"} 0
-node_softnet_times_squeezed_total{cpu="3"} 0
-node_softnet_times_squeezed_total{cpu="4"} 0
-node_softnet_times_squeezed_total{cpu="5"} 0
-node_softnet_times_squeezed_total{cpu="6"} 0
-node_softnet_times_squeezed_total{cpu="7"} 0
-node_softnet_times_squeezed_total{cpu="8"} 0
-node_softnet_times_squeezed_total{cpu="9"} 0
+        target.timestamps = TimestampPolicy::StampCached;
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let first =
+            adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+        assert_eq!(first.samples[0].timestamp, Some(1700000000123));
+        assert_eq!(first.samples[1].timestamp, None);
+        let second =
+            adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new());
+        assert_eq!(second.samples[0].timestamp, Some(1700000000123));
+        assert!(second.samples[1].timestamp.unwrap() >= before);
+    }
+
+    #[test]
+    fn test_proxy_family_at_a_time() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: http_requests_total
+  actions:
+  - aggregate:
+      operation: sum
+      by: [code]
 "#,
+            )
+            .unwrap(),
         );
-        let exp_ = TestPayload::from_text(
-            r#"
-# HELP node_softnet_times_squeezed_total Number of times processing packets ran out of quota
-# TYPE node_softnet_times_squeezed_total counter
-node_softnet_times_squeezed_total{cpu="1"} 0
+        let text = include_str!("../fixtures/rust_prometheus.prom");
+
+        let mut parser = Parser::new(TextFormat::Prometheus);
+        let mut families = vec![];
+        for line in text.lines() {
+            families.extend(parser.parse_line(line).unwrap());
+        }
+        families.push(parser.finish());
+        for family in &families {
+            assert!(!family.samples.is_empty());
+            assert!(family
+                .samples
+                .iter()
+                .all(|sample| sample.metric == family.samples[0].metric));
+        }
+        assert_eq!(families.len(), 10);
+
+        let mut state = FilterState::new();
+        for family in families {
+            adapter
+                .filter_family(&mut state, family, &HashMap::new())
+                .unwrap();
+        }
+        let streamed = ExpositionFormat::Prometheus.render(&state.finish());
+        let whole = ExpositionFormat::Prometheus.render(
+            &adapter.apply_filters(TestPayload::from_text(text).parsed_scrape, &HashMap::new()),
+        );
+        pretty_assert_eq!(
+            std::str::from_utf8(&whole).unwrap(),
+            std::str::from_utf8(&streamed).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_proxy_family_order() {
+        let text = r#"
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 0.5
+# HELP http_requests_total Total HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="500"} 3
+http_requests_total{code="200"} 1027
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le="+Inf"} 2
+rpc_duration_seconds_sum 0.5
+rpc_duration_seconds_count 2
+build_info{version="1.0"} 1
+"#;
+        let scrape = Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap();
+        let render = |preserve_order| {
+            let chunks = ExpositionFormat::Prometheus
+                .render_chunks(&scrape, preserve_order)
+                .collect::<Vec<_>>();
+            String::from_utf8(chunks.concat()).unwrap()
+        };
+        pretty_assert_eq!(
+            r#"# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 5e-1
+# HELP http_requests_total Total HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="500"} 3e0
+http_requests_total{code="200"} 1.027e3
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le="+Inf"} 2e0
+rpc_duration_seconds_sum 5e-1
+rpc_duration_seconds_count 2e0
+build_info{version="1.0"} 1e0
 "#,
+            render(true)
         );
-        let filtered = adapter.apply_filters(inp_.parsed_scrape);
-        let out_ = TestPayload::from_scrape(filtered);
-        pretty_assert_eq!(exp_.sorted_text.as_str(), out_.sorted_text.as_str());
+        pretty_assert_eq!(
+            r#"build_info{version="1.0"} 1e0
+# HELP http_requests_total Total HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{code="500"} 3e0
+http_requests_total{code="200"} 1.027e3
+# HELP node_load1 1m load average.
+# TYPE node_load1 gauge
+node_load1 5e-1
+# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{le="+Inf"} 2e0
+rpc_duration_seconds_sum 5e-1
+rpc_duration_seconds_count 2e0
+"#,
+            render(false)
+        );
+    }
+
+    type ReferenceSample = (String, Vec<(String, String)>, String);
+
+    /// Parses HELP and sample lines following the grammar of the exposition
+    /// formats, independently of `Scrape::parse`, and panics on lines that
+    /// do not follow it.  Returns the HELP text of every metric, and the
+    /// metric name, labels and value of every sample.
+    fn reference_parse(
+        text: &str,
+        format: ExpositionFormat,
+    ) -> (HashMap<String, String>, Vec<ReferenceSample>) {
+        fn unescape(s: &str, quotes: bool) -> String {
+            let mut unescaped = String::new();
+            let mut escaping = false;
+            for c in s.chars() {
+                if escaping {
+                    match c {
+                        'n' => unescaped.push('\n'),
+                        '\\' => unescaped.push('\\'),
+                        '"' if quotes => unescaped.push('"'),
+                        other => panic!("invalid escape sequence \\{other} in {s:?}"),
+                    }
+                    escaping = false;
+                } else if c == '\\' {
+                    escaping = true;
+                } else {
+                    unescaped.push(c);
+                }
+            }
+            assert!(!escaping, "dangling backslash in {s:?}");
+            unescaped
+        }
+
+        let help_re = regex::Regex::new(r"^# HELP ([a-zA-Z_:][a-zA-Z0-9_:]*) (.*)$").unwrap();
+        let sample_re = regex::Regex::new(
+            r#"^([a-zA-Z_:][a-zA-Z0-9_:]*)(?:\{((?:[a-zA-Z_][a-zA-Z0-9_]*="(?:[^"\\\n]|\\.)*",?)*)\})? (\S+)(?: \S+)?(?: # \{.*\} \S+(?: \S+)?)?$"#,
+        )
+        .unwrap();
+        let label_re =
+            regex::Regex::new(r#"([a-zA-Z_][a-zA-Z0-9_]*)="((?:[^"\\\n]|\\.)*)""#).unwrap();
+
+        let mut helps = HashMap::new();
+        let mut samples = vec![];
+        for line in text.lines() {
+            if let Some(captures) = help_re.captures(line) {
+                let quotes = format == ExpositionFormat::OpenMetrics;
+                helps.insert(captures[1].to_string(), unescape(&captures[2], quotes));
+            } else if line.starts_with('#') || line.is_empty() {
+                continue;
+            } else {
+                let captures = sample_re
+                    .captures(line)
+                    .unwrap_or_else(|| panic!("unparseable line {line:?}"));
+                let labels = captures.get(2).map_or(vec![], |labels| {
+                    label_re
+                        .captures_iter(labels.as_str())
+                        .map(|label| (label[1].to_string(), unescape(&label[2], true)))
+                        .collect()
+                });
+                let value = captures[3].parse::<f64>().unwrap().to_string();
+                samples.push((captures[1].to_string(), labels, value));
+            }
+        }
+        (helps, samples)
+    }
+
+    proptest! {
+        #[test]
+        fn test_escaping(
+            label_value in any::<String>(),
+            help in any::<String>().prop_filter("HELP text is trimmed", |h| {
+                !h.is_empty() && h.trim() == h
+            }),
+        ) {
+            let scrape = Scrape {
+                docs: HashMap::from([("test_metric".to_string(), help.clone())]),
+                units: HashMap::new(),
+                samples: vec![Sample {
+                    metric: "test_metric".to_string(),
+                    labels: Labels::from([("label".to_string(), label_value.clone())]),
+                    value: Value::Gauge(1.0),
+                    created: None,
+                    timestamp: None,
+                    exemplar: None,
+                }],
+            };
+            for (format, text_format) in [
+                (ExpositionFormat::Prometheus, TextFormat::Prometheus),
+                (ExpositionFormat::OpenMetrics, TextFormat::OpenMetrics),
+            ] {
+                let rendered = format.render(&scrape);
+                let rendered = std::str::from_utf8(rendered.as_ref()).unwrap();
+
+                let (helps, samples) = reference_parse(rendered, format);
+                prop_assert_eq!(helps.get("test_metric"), Some(&help));
+                prop_assert_eq!(
+                    samples,
+                    vec![(
+                        "test_metric".to_string(),
+                        vec![("label".to_string(), label_value.clone())],
+                        "1".to_string()
+                    )]
+                );
+
+                let reparsed =
+                    Scrape::parse_format(rendered.lines().map(|s| Ok(s.to_owned())), text_format)
+                        .unwrap();
+                prop_assert_eq!(&reparsed.docs, &scrape.docs);
+                prop_assert_eq!(&reparsed.samples, &scrape.samples);
+            }
+        }
     }
 
     #[test]
@@ -479,7 +3472,7 @@ node_softnet_times_squeezed_total{cpu="1"} 0
 node_frobnicated{cpu="0"} 0
 "#,
         );
-        let first_filtered = adapter.apply_filters(first_input.parsed_scrape);
+        let first_filtered = adapter.apply_filters(first_input.parsed_scrape, &HashMap::new());
         let first_output = TestPayload::from_scrape(first_filtered);
         pretty_assert_eq!(
             first_input.sorted_text.as_str(),
@@ -497,8 +3490,9 @@ node_frobnicated{cpu="0"} 0
 node_frobnicated{cpu="0"} 25
 "#,
         );
-        let second_output =
-            TestPayload::from_scrape(adapter.apply_filters(second_input.parsed_scrape.clone()));
+        let second_output = TestPayload::from_scrape(
+            adapter.apply_filters(second_input.parsed_scrape.clone(), &HashMap::new()),
+        );
         pretty_assert_eq!(
             first_input.sorted_text.as_str(),
             second_output.sorted_text.as_str()
@@ -510,11 +3504,509 @@ node_frobnicated{cpu="0"} 25
         // time has passed, then the filter will let the updated value pass.
         // In other words, the output of this filter round should be the
         // input of the prior (-> the second) round.
-        let third_output =
-            TestPayload::from_scrape(adapter.apply_filters(second_input.parsed_scrape.clone()));
+        let third_output = TestPayload::from_scrape(
+            adapter.apply_filters(second_input.parsed_scrape.clone(), &HashMap::new()),
+        );
         pretty_assert_eq!(
             second_input.sorted_text.as_str(),
             third_output.sorted_text.as_str()
         );
     }
+
+    #[test]
+    fn test_caching_dropped_samples() {
+        let adapter = make_adapter_filter_tester(
+            serde_yaml::from_str(
+                r#"
+- regex: node_frobnicated
+  actions:
+  - reduce_time_resolution:
+      resolution: 1h
+- source_labels: [cpu]
+  regex: "1"
+  actions: [drop]
+"#,
+            )
+            .unwrap(),
+        );
+        let input = TestPayload::from_text(
+            r#"
+# TYPE node_frobnicated counter
+node_frobnicated{cpu="0"} 0
+node_frobnicated{cpu="1"} 0
+"#,
+        );
+        let samples = input.parsed_scrape.samples.clone();
+        let filtered = adapter.apply_filters(input.parsed_scrape, &HashMap::new());
+        assert_eq!(filtered.samples.len(), 1);
+
+        // Only the sample that was kept has been cached.
+        let cache = adapter.cache.lock().unwrap();
+        let now = std::time::Instant::now();
+        let staleness = Duration::from_secs(3600);
+        assert!(cache.get(&samples[0], now, staleness).is_some());
+        assert!(cache.get(&samples[1], now, staleness).is_none());
+    }
+
+    /// Serves metrics to requests bearing the expected credentials and
+    /// headers, and returns the URL they are served at.
+    fn serve_protected_metrics() -> url::Url {
+        async fn metrics(headers: axum::http::HeaderMap) -> (StatusCode, &'static str) {
+            if headers.get("authorization").map(|v| v.as_bytes()) != Some(b"Bearer s3cr3t")
+                || headers.get("x-scope-orgid").map(|v| v.as_bytes()) != Some(b"tenant1")
+            {
+                return (StatusCode::UNAUTHORIZED, "Unauthorized\n");
+            }
+            (StatusCode::OK, "# TYPE node_load1 gauge\nnode_load1 0.5\n")
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/metrics", axum::routing::get(metrics));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        url::Url::from_str(&format!("http://{addr}/metrics")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_backend_authentication() {
+        let url = serve_protected_metrics();
+        let token = std::env::temp_dir().join(format!("backend-token-{}", std::process::id()));
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url = url;
+        target.connect_to[0].bearer_token_file = Some(SecretFile::new(token.clone()));
+        target.connect_to[0]
+            .headers
+            .insert("x-scope-orgid", "tenant1".parse().unwrap());
+        let adapter = crate::proxy::MetricsProxier::from(target);
+
+        std::fs::write(&token, "wrong").unwrap();
+        let (status, _, _) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // The token is read again once rotated.
+        std::fs::write(&token, "s3cr3t\n").unwrap();
+        let (status, _, body) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(
+            "# TYPE node_load1 gauge\nnode_load1 5e-1\n",
+            std::str::from_utf8(&body).unwrap()
+        );
+
+        std::fs::remove_file(&token).unwrap();
+        let (status, _, _) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_relayed_headers() {
+        let mut client = HeaderMap::new();
+        client.insert("accept", "text/plain".parse().unwrap());
+        client.insert("authorization", "Bearer s3cr3t".parse().unwrap());
+        client.insert("connection", "close".parse().unwrap());
+        client.insert("x-tenant", "tenant1".parse().unwrap());
+        client.append("x-tenant", "tenant2".parse().unwrap());
+
+        let relayed = safely_clone_request_headers(client.clone(), &RequestHeaders::default());
+        assert_eq!(vec!["accept"], relayed.keys().collect::<Vec<_>>());
+
+        let policy = RequestHeaders {
+            forward: vec!["authorization".into(), "connection".into()],
+            rename: HashMap::from([("x-tenant".into(), "x-scope-orgid".into())]),
+        };
+        let relayed = safely_clone_request_headers(client, &policy);
+        assert_eq!(
+            Some("Bearer s3cr3t"),
+            relayed.get("authorization").map(|v| v.to_str().unwrap())
+        );
+        // Hop-by-hop headers are never relayed.
+        assert!(relayed.get("connection").is_none());
+        assert!(relayed.get("accept").is_none());
+        assert!(relayed.get("x-tenant").is_none());
+        assert_eq!(
+            vec!["tenant1", "tenant2"],
+            relayed
+                .get_all("x-scope-orgid")
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+
+        let mut backend = HeaderMap::new();
+        backend.insert("content-type", "text/plain".parse().unwrap());
+        backend.insert("content-length", "10".parse().unwrap());
+        backend.insert("transfer-encoding", "chunked".parse().unwrap());
+        backend.insert("x-backend-version", "1.0".parse().unwrap());
+        backend.insert("set-cookie", "a=b".parse().unwrap());
+        backend.append("set-cookie", "c=d".parse().unwrap());
+
+        let relayed = safely_clone_response_headers(backend.clone(), &ResponseHeaders::default());
+        assert_eq!(
+            vec![
+                "content-type",
+                "x-backend-version",
+                "set-cookie",
+                "set-cookie"
+            ],
+            relayed.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>()
+        );
+
+        let policy = ResponseHeaders {
+            forward: None,
+            drop: vec!["set-cookie".into()],
+        };
+        let relayed = safely_clone_response_headers(backend.clone(), &policy);
+        assert_eq!(
+            vec!["content-type", "x-backend-version"],
+            relayed.keys().collect::<Vec<_>>()
+        );
+
+        let policy = ResponseHeaders {
+            forward: Some(vec!["content-type".into(), "content-length".into()]),
+            drop: vec![],
+        };
+        let relayed = safely_clone_response_headers(backend, &policy);
+        assert_eq!(vec!["content-type"], relayed.keys().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_relayed_credentials() {
+        let url = serve_protected_metrics();
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url = url;
+        target.request_headers = RequestHeaders {
+            forward: vec!["authorization".into()],
+            rename: HashMap::from([("x-tenant".into(), "x-scope-orgid".into())]),
+        };
+        let adapter = crate::proxy::MetricsProxier::from(target);
+
+        let (status, _, _) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer s3cr3t".parse().unwrap());
+        headers.insert("x-tenant", "tenant1".parse().unwrap());
+        let (status, _, _) = adapter.handle(headers, HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_query_parameters() {
+        // Echoes the query string of each request in a label.
+        async fn probe(uri: axum::http::Uri) -> String {
+            format!(
+                "probe_success{{query=\"{}\"}} 1\n",
+                uri.query().unwrap_or_default()
+            )
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/probe", axum::routing::get(probe));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url =
+            url::Url::from_str(&format!("http://{addr}/probe?module=http_2xx&debug=false"))
+                .unwrap();
+        target.query_parameters = serde_yaml::from_str(
+            r#"
+forward: [target, module]
+set:
+  debug: "true"
+validate:
+  target: "[a-z0-9.]+(:[0-9]+)?"
+"#,
+        )
+        .unwrap();
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let query = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let (status, _, body) = adapter
+            .handle(
+                HeaderMap::new(),
+                query(&[
+                    ("target", "example.com:443"),
+                    ("format", "json"),
+                    ("other", "x"),
+                ]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(
+            "probe_success{query=\"module=http_2xx&target=example.com%3A443&debug=true\"} 1e0\n",
+            std::str::from_utf8(&body).unwrap()
+        );
+
+        let (status, _, body) = adapter
+            .handle(
+                HeaderMap::new(),
+                query(&[("target", "localhost"), ("module", "icmp")]),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(
+            "probe_success{query=\"target=localhost&module=icmp&debug=true\"} 1e0\n",
+            std::str::from_utf8(&body).unwrap()
+        );
+
+        let (status, _, _) = adapter
+            .handle(HeaderMap::new(), query(&[("target", "http://evil/")]))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_merge_families() {
+        let parse =
+            |text: &str| vec![Scrape::parse(text.lines().map(|s| Ok(s.to_owned()))).unwrap()];
+        let backends = || {
+            vec![
+                parse(
+                    "# HELP up First backend.\n# TYPE up gauge\nup 1\n# TYPE node_load1 gauge\nnode_load1 0.5\n",
+                ),
+                parse(
+                    "# HELP up Second backend.\n# TYPE up gauge\nup 0\n# HELP node_load1 Cumulative load.\n# TYPE node_load1 counter\nnode_load1 7\n# TYPE process_cpu_seconds_total counter\nprocess_cpu_seconds_total 3\n",
+                ),
+            ]
+        };
+        let render = |families: Vec<Scrape>| {
+            let mut merged = Scrape {
+                docs: HashMap::new(),
+                units: HashMap::new(),
+                samples: vec![],
+            };
+            for family in families {
+                merged.extend(family);
+            }
+            ExpositionFormat::Prometheus.render(&merged)
+        };
+
+        pretty_assert_eq!(
+            render(merge_families(backends(), MergeConflictPolicy::First).unwrap()),
+            "# HELP up First backend.\n# TYPE up gauge\nup 1e0\n# TYPE node_load1 gauge\nnode_load1 5e-1\n# TYPE process_cpu_seconds_total counter\nprocess_cpu_seconds_total 3e0\n"
+        );
+        pretty_assert_eq!(
+            render(merge_families(backends(), MergeConflictPolicy::Last).unwrap()),
+            "# HELP up Second backend.\n# TYPE up gauge\nup 0e0\n# HELP node_load1 Cumulative load.\n# TYPE node_load1 counter\nnode_load1 7e0\n# TYPE process_cpu_seconds_total counter\nprocess_cpu_seconds_total 3e0\n"
+        );
+        assert!(merge_families(backends(), MergeConflictPolicy::Fail).is_err());
+
+        // Series differing only in their labels do not conflict.
+        let distinct = vec![parse("up{job=\"a\"} 1\n"), parse("up{job=\"b\"} 1\n")];
+        let merged = merge_families(distinct, MergeConflictPolicy::Fail).unwrap();
+        assert_eq!(2, merged.iter().map(|f| f.samples.len()).sum::<usize>());
+    }
+
+    /// Serves fixed metrics, and returns the URL they are served at.
+    fn serve_metrics(status: StatusCode, metrics: &'static str) -> url::Url {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/metrics",
+            axum::routing::get(move || async move { (status, metrics) }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        url::Url::from_str(&format!("http://{addr}/metrics")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_malformed_metrics() {
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url = serve_metrics(
+            StatusCode::OK,
+            "# TYPE node_load1 gauge\nnode_load1 0.5\nnode_load5 high\n",
+        );
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let (status, _, body) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("malformed line 3: node_load5 high"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_streaming() {
+        // The backend sends the first metric family, and the rest only
+        // once the test has received the first family from the proxy.
+        let backend_sender = Arc::new(Mutex::new(None));
+        let sender_slot = backend_sender.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/metrics",
+            axum::routing::get(move || {
+                let backend_sender = backend_sender.clone();
+                async move {
+                    let (mut sender, body) = hyper::Body::channel();
+                    sender
+                        .try_send_data(Bytes::from(
+                            "# TYPE node_load1 gauge\nnode_load1 0.5\nnode_load5 0.7\n",
+                        ))
+                        .unwrap();
+                    *backend_sender.lock().unwrap() = Some(sender);
+                    axum::response::Response::new(axum::body::boxed(body))
+                }
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url = url::Url::from_str(&format!("http://{addr}/metrics")).unwrap();
+        let adapter = crate::proxy::MetricsProxier::from(target);
+
+        let (status, _, mut body) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        let first = hyper::body::HttpBody::data(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, "# TYPE node_load1 gauge\nnode_load1 5e-1\n");
+
+        // A malformed line once the response has started aborts it.
+        let mut sender = sender_slot.lock().unwrap().take().unwrap();
+        sender
+            .send_data(Bytes::from("node_load5 high\n"))
+            .await
+            .unwrap();
+        drop(sender);
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_streaming_served_family() {
+        let mut target = make_test_proxy_target(
+            serde_yaml::from_str(
+                r#"
+- regex: node_load5
+  actions:
+  - rename_metric:
+      regex: node_load5
+      replacement: node_load1
+"#,
+            )
+            .unwrap(),
+        );
+        target.connect_to[0].url = serve_metrics(
+            StatusCode::OK,
+            "# TYPE node_load1 gauge\nnode_load1 0.5\n# TYPE node_load5 gauge\nnode_load5{cpu=\"0\"} 0.7\n",
+        );
+        let render = |target: HttpProxyTarget| async {
+            let adapter = crate::proxy::MetricsProxier::from(target);
+            let (status, _, body) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+            assert_eq!(status, StatusCode::OK);
+            String::from_utf8(hyper::body::to_bytes(body).await.unwrap().to_vec()).unwrap()
+        };
+
+        // Samples moved into a family already streamed are dropped.
+        pretty_assert_eq!(
+            "# TYPE node_load1 gauge\nnode_load1 5e-1\n",
+            render(target.clone()).await
+        );
+        // Responses rendered in full keep them.
+        target.preserve_order = false;
+        pretty_assert_eq!(
+            "# TYPE node_load1 gauge\nnode_load1 5e-1\nnode_load1{cpu=\"0\"} 7e-1\n",
+            render(target).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_limits_in_later_family() {
+        let mut target = make_test_proxy_target(vec![]);
+        target.connect_to[0].url = serve_metrics(
+            StatusCode::OK,
+            "# TYPE node_load1 gauge\nnode_load1 0.5\n# TYPE node_load5 gauge\nnode_load5{cpu=\"0\"} 0.7\nnode_load5{cpu=\"1\"} 0.3\n",
+        );
+        target.limits.sample_limit = Some(2);
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let (status, _, body) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(
+            body.contains("The target exceeded the configured limits."),
+            "{body}"
+        );
+        assert!(body.contains("exceeding sample_limit"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_multiple_backends() {
+        let mut target = make_test_proxy_target(vec![]);
+        let backend = target.connect_to[0].clone();
+        target.connect_to = vec![
+            ConnectTo {
+                url: serve_metrics(StatusCode::OK, "# TYPE up gauge\nup 1\nnode_load1 0.5\n"),
+                labels: HashMap::from([("exporter".to_string(), "node".to_string())]),
+                ..backend.clone()
+            },
+            ConnectTo {
+                url: serve_metrics(StatusCode::OK, "# TYPE up gauge\nup 1\nnode_load1 0.7\n"),
+                labels: HashMap::from([("exporter".to_string(), "sidecar".to_string())]),
+                ..backend.clone()
+            },
+            ConnectTo {
+                url: serve_metrics(StatusCode::SERVICE_UNAVAILABLE, "Unavailable\n"),
+                ..backend
+            },
+        ];
+        let body = |body: hyper::Body| async {
+            String::from_utf8(hyper::body::to_bytes(body).await.unwrap().to_vec()).unwrap()
+        };
+
+        let adapter = crate::proxy::MetricsProxier::from(target.clone());
+        let (status, _, response) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!("Unavailable\n", body(response).await);
+
+        target.merge.on_failure = crate::config::BackendFailurePolicy::ServePartial;
+        let adapter = crate::proxy::MetricsProxier::from(target.clone());
+        let (status, _, response) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        pretty_assert_eq!(
+            "# TYPE up gauge\nup{exporter=\"node\"} 1e0\nup{exporter=\"sidecar\"} 1e0\nnode_load1{exporter=\"node\"} 5e-1\nnode_load1{exporter=\"sidecar\"} 7e-1\n",
+            body(response).await
+        );
+
+        // Without labels telling them apart, the backends conflict.
+        target.connect_to.truncate(2);
+        for connect_to in &mut target.connect_to {
+            connect_to.labels.clear();
+        }
+        target.merge.on_conflict = MergeConflictPolicy::Fail;
+        let adapter = crate::proxy::MetricsProxier::from(target.clone());
+        let (status, _, _) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        target.merge.on_conflict = MergeConflictPolicy::Last;
+        let adapter = crate::proxy::MetricsProxier::from(target);
+        let (status, _, response) = adapter.handle(HeaderMap::new(), HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        pretty_assert_eq!(
+            "# TYPE up gauge\nup 1e0\nnode_load1 7e-1\n",
+            body(response).await
+        );
+    }
 }
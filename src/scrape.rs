@@ -0,0 +1,590 @@
+//! Data model for scraped metrics, and parser for the text formats
+//! exporters serve them in.
+//!
+//! The parser understands both the Prometheus text exposition format
+//! (version 0.0.4) and the OpenMetrics text format (version 1.0.0),
+//! since the proxy relays the `Accept` header of its clients to the
+//! backend, which may then choose to respond in either format.
+//! Lines that belong to the same histogram or summary (buckets,
+//! quantiles, `_sum`, `_count` and `_created`) are gathered into a
+//! single `Sample`, as are counters and their `_created` lines.
+//! Exemplars stay attached to the counter or bucket they were served with.
+//!
+//! The proxy used to rely on the `prometheus-parse` crate, which only
+//! understands the Prometheus text format: it has no notion of `# UNIT`,
+//! `_created` lines, exemplars or `# EOF`, reads timestamps as milliseconds
+//! only, and needs the whole exposition at once.  Supporting OpenMetrics
+//! backends and parsing responses as they are received required a parser
+//! of our own.
+
+use itertools::Itertools;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+/// Label names mapped to label values, ordered by label name.
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+/// An example of an observation counted by a counter or histogram
+/// bucket, such as the trace of a request, as served in OpenMetrics.
+pub struct Exemplar {
+    pub labels: Labels,
+    pub value: f64,
+    /// Time of the observation, in seconds since the epoch, if the
+    /// backend specified one.
+    pub timestamp: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramCount {
+    pub less_than: f64,
+    pub count: f64,
+    pub exemplar: Option<Exemplar>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryCount {
+    pub quantile: f64,
+    pub count: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Histogram {
+    pub buckets: Vec<HistogramCount>,
+    pub sum: Option<f64>,
+    pub count: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Summary {
+    pub quantiles: Vec<SummaryCount>,
+    pub sum: Option<f64>,
+    pub count: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The value of a sample, which also determines the type of its metric.
+pub enum Value {
+    Counter(f64),
+    Gauge(f64),
+    Histogram(Histogram),
+    Summary(Summary),
+    Untyped(f64),
+}
+
+impl Value {
+    /// Returns the value of counters, gauges and untyped metrics.
+    pub fn scalar(&self) -> Option<f64> {
+        match self {
+            Value::Untyped(val) | Value::Counter(val) | Value::Gauge(val) => Some(*val),
+            Value::Histogram(_) | Value::Summary(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A single time series as returned by a backend (or, for histograms
+/// and summaries, all the time series that share the same labels).
+pub struct Sample {
+    pub metric: String,
+    pub labels: Labels,
+    pub value: Value,
+    /// Creation time of counters, histograms and summaries, in seconds
+    /// since the epoch, as served by `_created` lines in OpenMetrics.
+    pub created: Option<f64>,
+    /// Time of the sample, in milliseconds since the epoch, if the
+    /// backend specified one.
+    pub timestamp: Option<i64>,
+    /// Exemplar of counters.  Exemplars of histograms are kept
+    /// with their buckets.
+    pub exemplar: Option<Exemplar>,
+}
+
+impl Sample {
+    /// Returns the number of time series this sample is exposed as.
+    pub fn series_count(&self) -> usize {
+        let optional = |v: &Option<f64>| usize::from(v.is_some());
+        match &self.value {
+            Value::Untyped(_) | Value::Counter(_) | Value::Gauge(_) => 1,
+            Value::Histogram(h) => h.buckets.len() + optional(&h.sum) + optional(&h.count),
+            Value::Summary(s) => s.quantiles.len() + optional(&s.sum) + optional(&s.count),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// All samples returned by a backend, along with the documentation
+/// and unit of each metric name.
+pub struct Scrape {
+    pub docs: HashMap<String, String>,
+    pub units: HashMap<String, String>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The text formats metrics can be exposed in.  They only differ, as
+/// far as parsing goes, in the unit of sample timestamps.
+pub enum TextFormat {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl TextFormat {
+    /// Determines the format of a response from its `Content-Type`.
+    pub fn from_content_type(content_type: &str) -> Self {
+        if content_type
+            .trim_start()
+            .to_lowercase()
+            .starts_with("application/openmetrics-text")
+        {
+            TextFormat::OpenMetrics
+        } else {
+            TextFormat::Prometheus
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+impl MetricType {
+    fn parse(s: &str) -> MetricType {
+        match s {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            "summary" => MetricType::Summary,
+            _ => MetricType::Untyped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The part a line plays in the sample it belongs to.
+enum Role {
+    Scalar,
+    Created,
+    Bucket,
+    Quantile,
+    Sum,
+    Count,
+}
+
+/// Where a line belongs: the family (name in `# HELP` and `# TYPE`
+/// lines) and its type, the name of the sample, and its role in it.
+struct Classification {
+    family: String,
+    family_type: MetricType,
+    metric: String,
+    role: Role,
+}
+
+/// Classifies a line according to its name and the types declared so far.
+fn classify(name: &str, types: &HashMap<String, MetricType>) -> Classification {
+    let found = |family: &str, family_type, metric: &str, role| Classification {
+        family: family.to_string(),
+        family_type,
+        metric: metric.to_string(),
+        role,
+    };
+    match types.get(name) {
+        Some(MetricType::Summary) => {
+            return found(name, MetricType::Summary, name, Role::Quantile);
+        }
+        Some(t @ (MetricType::Counter | MetricType::Gauge | MetricType::Untyped)) => {
+            return found(name, *t, name, Role::Scalar);
+        }
+        _ => {}
+    }
+    for suffix in ["_total", "_created", "_bucket", "_sum", "_count"] {
+        let Some(base) = name.strip_suffix(suffix) else {
+            continue;
+        };
+        match (suffix, types.get(base).copied()) {
+            ("_total", Some(MetricType::Counter)) => {
+                return found(base, MetricType::Counter, name, Role::Scalar);
+            }
+            ("_created", Some(MetricType::Counter)) => {
+                return found(
+                    base,
+                    MetricType::Counter,
+                    &format!("{base}_total"),
+                    Role::Created,
+                );
+            }
+            ("_created", Some(t @ (MetricType::Histogram | MetricType::Summary))) => {
+                return found(base, t, base, Role::Created);
+            }
+            ("_bucket", Some(MetricType::Histogram)) => {
+                return found(base, MetricType::Histogram, base, Role::Bucket);
+            }
+            ("_sum", Some(t @ (MetricType::Histogram | MetricType::Summary))) => {
+                return found(base, t, base, Role::Sum);
+            }
+            ("_count", Some(t @ (MetricType::Histogram | MetricType::Summary))) => {
+                return found(base, t, base, Role::Count);
+            }
+            ("_created", None) => {
+                // Counters typed with their full name, as in the Prometheus format.
+                let total = format!("{base}_total");
+                if types.get(&total) == Some(&MetricType::Counter) {
+                    return found(&total, MetricType::Counter, &total, Role::Created);
+                }
+            }
+            _ => {}
+        }
+    }
+    found(name, MetricType::Untyped, name, Role::Scalar)
+}
+
+/// Parses a float the way Go does, which is what most exporters use.
+fn parse_float(s: &str) -> Option<f64> {
+    match s {
+        "NaN" | "nan" => Some(f64::NAN),
+        "+Inf" | "Inf" | "+inf" | "inf" => Some(f64::INFINITY),
+        "-Inf" | "-inf" => Some(f64::NEG_INFINITY),
+        _ => s.parse::<f64>().ok(),
+    }
+}
+
+struct SampleLine<'a> {
+    name: &'a str,
+    labels: Labels,
+    value: f64,
+    timestamp: Option<i64>,
+    exemplar: Option<Exemplar>,
+}
+
+/// Parses a timestamp, in milliseconds in the Prometheus format,
+/// or in (possibly fractional) seconds in the OpenMetrics format.
+fn parse_timestamp(s: &str, format: TextFormat) -> Option<i64> {
+    match format {
+        TextFormat::Prometheus => s.parse::<i64>().ok(),
+        TextFormat::OpenMetrics => parse_float(s)
+            .filter(|secs| secs.is_finite())
+            .map(|secs| (secs * 1000.0).round() as i64),
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+/// Resolves the escape sequences of label values and HELP text.  Both
+/// formats escape backslashes and line feeds, and label values (as well
+/// as HELP text in OpenMetrics) escape double quotes too.  Any other
+/// backslash is kept as is.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('"') => unescaped.push('"'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Parses a sample line, along with its exemplar if any.
+/// Returns `None` for malformed lines.
+fn parse_sample_line(line: &str, format: TextFormat) -> Option<SampleLine<'_>> {
+    let name_end = line.find(|c: char| !is_name_char(c)).unwrap_or(line.len());
+    if name_end == 0 {
+        return None;
+    }
+    let name = &line[..name_end];
+    let (labels, rest) = match line[name_end..].strip_prefix('{') {
+        Some(after_brace) => parse_labels(after_brace)?,
+        None => (Labels::new(), &line[name_end..]),
+    };
+
+    // Exemplars, which start with a #, may follow the timestamp.
+    // Exemplars that cannot be parsed are ignored.
+    let (rest, exemplar) = match rest.split_once('#') {
+        Some((rest, exemplar)) => (rest, parse_exemplar(exemplar)),
+        None => (rest, None),
+    };
+    let mut fields = rest.split_whitespace();
+    let value = parse_float(fields.next()?)?;
+    let timestamp = match fields.next() {
+        Some(field) => Some(parse_timestamp(field, format)?),
+        None => None,
+    };
+    Some(SampleLine {
+        name,
+        labels,
+        value,
+        timestamp,
+        exemplar,
+    })
+}
+
+/// Parses the labels following an opening brace, and returns them
+/// along with the rest of the line after the closing brace.
+fn parse_labels(mut rest: &str) -> Option<(Labels, &str)> {
+    let mut labels = Labels::new();
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        if let Some(after_brace) = rest.strip_prefix('}') {
+            return Some((labels, after_brace));
+        }
+        let label_end = rest.find(|c: char| !is_name_char(c))?;
+        if label_end == 0 {
+            return None;
+        }
+        let label_name = &rest[..label_end];
+        rest = rest[label_end..].trim_start().strip_prefix('=')?;
+        rest = rest.trim_start().strip_prefix('"')?;
+        let mut escaped = false;
+        let value_end = rest.find(|c: char| {
+            let end = !escaped && c == '"';
+            escaped = !escaped && c == '\\';
+            end
+        })?;
+        labels.insert(label_name.to_string(), unescape(&rest[..value_end]));
+        rest = &rest[value_end + 1..];
+    }
+}
+
+/// Parses an exemplar (what follows the # after a sample), whose
+/// timestamp is always in seconds.
+fn parse_exemplar(s: &str) -> Option<Exemplar> {
+    let (labels, rest) = parse_labels(s.trim_start().strip_prefix('{')?)?;
+    let mut fields = rest.split_whitespace();
+    let value = parse_float(fields.next()?)?;
+    let timestamp = match fields.next() {
+        Some(field) => Some(parse_float(field)?),
+        None => None,
+    };
+    Some(Exemplar {
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+impl Scrape {
+    /// Parses the lines of a text exposition in the Prometheus format.
+    pub fn parse(lines: impl Iterator<Item = io::Result<String>>) -> io::Result<Scrape> {
+        Scrape::parse_format(lines, TextFormat::Prometheus)
+    }
+
+    /// Parses the lines of a text exposition.  Any error reading lines
+    /// is returned, as is an error of kind `InvalidData` for the first
+    /// line that cannot be parsed.
+    pub fn parse_format(
+        lines: impl Iterator<Item = io::Result<String>>,
+        format: TextFormat,
+    ) -> io::Result<Scrape> {
+        let mut parser = Parser::new(format);
+        let mut scrape = Scrape::default();
+        for line in lines {
+            if let Some(family) = parser.parse_line(&line?)? {
+                scrape.extend(family);
+            }
+        }
+        scrape.extend(parser.finish());
+        Ok(scrape)
+    }
+
+    /// Adds the samples of another scrape (and their documentation
+    /// and units) to this one.
+    pub fn extend(&mut self, other: Scrape) {
+        self.docs.extend(other.docs);
+        self.units.extend(other.units);
+        self.samples.extend(other.samples);
+    }
+}
+
+/// Incremental parser of text expositions, fed one line at a time.
+/// Samples are returned one family at a time, as soon as a line of
+/// another family is parsed, since both formats require the lines
+/// of a family to be contiguous.
+pub struct Parser {
+    format: TextFormat,
+    docs: HashMap<String, String>,
+    units: HashMap<String, String>,
+    types: HashMap<String, MetricType>,
+    finished: bool,
+    line_number: usize,
+    // The family being parsed, and its samples in the order they first
+    // appear in.  Samples composed of several lines are located by
+    // metric name and labels.
+    family: String,
+    samples: Vec<Sample>,
+    index: HashMap<(String, Labels), usize>,
+}
+
+impl Parser {
+    pub fn new(format: TextFormat) -> Self {
+        Parser {
+            format,
+            docs: HashMap::new(),
+            units: HashMap::new(),
+            types: HashMap::new(),
+            finished: false,
+            line_number: 0,
+            family: String::new(),
+            samples: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    /// Parses a line.  If the line is the first of a family, the samples
+    /// of the previous family are returned.  Lines after `# EOF`, as well
+    /// as comments other than `# HELP`, `# TYPE` and `# UNIT`, are ignored.
+    ///
+    /// # Errors
+    /// An error of kind `InvalidData` if the line is not a comment and
+    /// cannot be parsed as a sample.
+    pub fn parse_line(&mut self, line: &str) -> io::Result<Option<Scrape>> {
+        self.line_number += 1;
+        let line = line.trim();
+        if line.is_empty() || self.finished {
+            return Ok(None);
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, char::is_whitespace);
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("HELP"), Some(name), Some(text)) => {
+                    self.docs.insert(name.to_string(), unescape(text.trim()));
+                }
+                (Some("TYPE"), Some(name), Some(kind)) => {
+                    self.types
+                        .insert(name.to_string(), MetricType::parse(kind.trim()));
+                }
+                (Some("UNIT"), Some(name), Some(unit)) => {
+                    self.units.insert(name.to_string(), unit.trim().to_string());
+                }
+                (Some("EOF"), None, None) => self.finished = true,
+                _ => {}
+            }
+            return Ok(None);
+        }
+        let line_number = self.line_number;
+        let malformed = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed line {line_number}: {line}"),
+            )
+        };
+        let parsed = parse_sample_line(line, self.format).ok_or_else(malformed)?;
+
+        let class = classify(parsed.name, &self.types);
+        let mut labels = parsed.labels;
+        let bound = match class.role {
+            Role::Bucket => labels.remove("le").and_then(|le| parse_float(&le)),
+            Role::Quantile => labels.remove("quantile").and_then(|q| parse_float(&q)),
+            _ => None,
+        };
+        if bound.is_none() && [Role::Bucket, Role::Quantile].contains(&class.role) {
+            return Err(malformed());
+        }
+
+        let previous = if class.family != self.family {
+            let previous = self.take_family();
+            self.family = class.family;
+            Some(previous).filter(|family| !family.samples.is_empty())
+        } else {
+            None
+        };
+
+        let key = (class.metric, labels);
+        let position = match self.index.get(&key) {
+            Some(position) => *position,
+            None => {
+                let value = match class.family_type {
+                    MetricType::Counter => Value::Counter(0.0),
+                    MetricType::Gauge => Value::Gauge(0.0),
+                    MetricType::Histogram => Value::Histogram(Histogram::default()),
+                    MetricType::Summary => Value::Summary(Summary::default()),
+                    MetricType::Untyped => Value::Untyped(0.0),
+                };
+                self.samples.push(Sample {
+                    metric: key.0.clone(),
+                    labels: key.1.clone(),
+                    value,
+                    created: None,
+                    timestamp: None,
+                    exemplar: None,
+                });
+                self.index.insert(key, self.samples.len() - 1);
+                self.samples.len() - 1
+            }
+        };
+
+        let val = parsed.value;
+        let sample = &mut self.samples[position];
+        // Lines of the same sample are expected to share their timestamp.
+        sample.timestamp = sample.timestamp.or(parsed.timestamp);
+        match (class.role, &mut sample.value) {
+            (Role::Created, _) => sample.created = Some(val),
+            (Role::Scalar, Value::Counter(v)) => {
+                *v = val;
+                sample.exemplar = parsed.exemplar;
+            }
+            // OpenMetrics only allows exemplars on counters and buckets.
+            (Role::Scalar, Value::Gauge(v) | Value::Untyped(v)) => *v = val,
+            (Role::Bucket, Value::Histogram(h)) => h.buckets.push(HistogramCount {
+                less_than: bound.unwrap(),
+                count: val,
+                exemplar: parsed.exemplar,
+            }),
+            (Role::Quantile, Value::Summary(s)) => s.quantiles.push(SummaryCount {
+                quantile: bound.unwrap(),
+                count: val,
+            }),
+            (Role::Sum, Value::Histogram(Histogram { sum, .. }))
+            | (Role::Sum, Value::Summary(Summary { sum, .. })) => *sum = Some(val),
+            (Role::Count, Value::Histogram(Histogram { count, .. }))
+            | (Role::Count, Value::Summary(Summary { count, .. })) => *count = Some(val),
+            _ => {}
+        }
+
+        Ok(previous)
+    }
+
+    /// Returns the samples of the last family parsed.
+    pub fn finish(mut self) -> Scrape {
+        self.take_family()
+    }
+
+    fn take_family(&mut self) -> Scrape {
+        self.index.clear();
+        let samples = std::mem::take(&mut self.samples);
+        // Documentation and units are keyed by family name in the
+        // exposition, but are kept under the name of the samples.
+        let mut docs = HashMap::new();
+        let mut units = HashMap::new();
+        for metric in samples.iter().map(|sample| &sample.metric).unique() {
+            if let Some(doc) = self.docs.get(&self.family) {
+                docs.insert(metric.clone(), doc.clone());
+            }
+            if let Some(unit) = self.units.get(&self.family) {
+                units.insert(metric.clone(), unit.clone());
+            }
+        }
+        Scrape {
+            docs,
+            units,
+            samples,
+        }
+    }
+}
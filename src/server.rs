@@ -1,16 +1,16 @@
 use crate::config::{self, HttpProxy, ListenerSpec};
 use crate::proxy;
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::http;
 use axum::http::StatusCode;
 use axum::middleware::map_response;
 use axum::{routing::get, Router};
 use axum_otel_metrics::HttpMetricsLayer;
 use hyper;
-use hyper::body::Bytes;
 use hyper::server::conn::AddrIncoming;
 use hyper_rustls::TlsAcceptor;
 use rustls;
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
 use std::time::Duration;
@@ -103,9 +103,19 @@ impl Server {
         // Short helper to issue backend request.
         async fn handle_with_proxy(
             State(proxy): State<proxy::MetricsProxier>,
-            headers: http::HeaderMap,
-        ) -> (StatusCode, http::HeaderMap, Bytes) {
-            proxy.handle(headers).await
+            Query(query): Query<HashMap<String, String>>,
+            mut headers: http::HeaderMap,
+        ) -> (StatusCode, http::HeaderMap, axum::body::BoxBody) {
+            // Clients that cannot set the Accept header may ask for JSON
+            // through the query string instead.
+            if query.get("format").map(String::as_str) == Some("json") {
+                headers.insert(
+                    http::header::ACCEPT,
+                    http::HeaderValue::from_static("application/json"),
+                );
+            }
+            let (status, headers, body) = proxy.handle(headers, query).await;
+            (status, headers, axum::body::boxed(body))
         }
 
         // Short helper to map 408 from request response timeout layer to 504.
@@ -131,13 +141,16 @@ impl Server {
             ServerKind::PrometheusMetricsProxy(config) => {
                 for (path, target) in config.handlers.clone() {
                     let cache_duration = target.clone().cache_duration;
+                    let relayed_headers = target.request_headers.relayed();
                     let state = proxy::MetricsProxier::from(target);
                     let mut method_router = get(handle_with_proxy)
                         .with_state(state)
                         .layer(tower::ServiceBuilder::new().layer(bodytimeout.clone()));
                     if Duration::from(cache_duration) > Duration::new(0, 0) {
-                        method_router = method_router
-                            .layer(crate::cache::CacheLayer::new(cache_duration.into()));
+                        method_router = method_router.layer(crate::cache::CacheLayer::new(
+                            cache_duration.into(),
+                            relayed_headers,
+                        ));
                     }
                     router = router.route(path.as_str(), method_router);
                 }